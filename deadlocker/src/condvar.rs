@@ -0,0 +1,95 @@
+//! Waiting for a condition on the locked fields, see the `wait_until` method of the generated
+//! states
+//!
+//! Waiting releases every lock of a state, so the condition variable can not be tied to any single
+//! mutex as with [std::sync::Condvar]. Instead, [Condvar] counts its notifications, and a waiter
+//! only blocks if no notification was sent since it last checked its condition.
+//!
+//! With the `tokio` feature, the `wait_until_async` method waits on a [Notify] instead.
+
+use std::sync::{Mutex, PoisonError};
+
+#[cfg(feature = "tokio")]
+pub use tokio::sync::Notify;
+
+/// A condition variable to wait on with the `wait_until` method of the generated states
+///
+/// Notifications sent after modifying the locked fields, whether or not the locks are still held,
+/// are never missed by a waiter which found its condition not to hold beforehand.
+#[derive(Debug, Default)]
+pub struct Condvar {
+    generation: Mutex<u64>,
+    condvar: std::sync::Condvar,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Condvar {
+            generation: Mutex::new(0),
+            condvar: std::sync::Condvar::new(),
+        }
+    }
+
+    /// Wakes up one thread waiting on the condition variable
+    pub fn notify_one(&self) {
+        *self.generation.lock().unwrap_or_else(PoisonError::into_inner) += 1;
+        self.condvar.notify_one();
+    }
+
+    /// Wakes up all threads waiting on the condition variable
+    pub fn notify_all(&self) {
+        *self.generation.lock().unwrap_or_else(PoisonError::into_inner) += 1;
+        self.condvar.notify_all();
+    }
+
+    /// Returns the number of notifications sent so far, to be passed to [Condvar::__wait]
+    #[doc(hidden)]
+    pub fn __generation(&self) -> u64 {
+        *self.generation.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Blocks until a notification is sent after `generation` was read, returning immediately if
+    /// one already was
+    #[doc(hidden)]
+    pub fn __wait(&self, generation: u64) {
+        let current = self.generation.lock().unwrap_or_else(PoisonError::into_inner);
+        drop(
+            self.condvar
+                .wait_while(current, |current| *current == generation)
+                .unwrap_or_else(PoisonError::into_inner),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::Arc, thread, time::Duration};
+
+    #[test]
+    fn wait_returns_if_notified_after_reading_generation() {
+        let condvar = Condvar::new();
+        let generation = condvar.__generation();
+        condvar.notify_one();
+
+        condvar.__wait(generation);
+    }
+
+    #[test]
+    fn wait_is_woken_by_notification() {
+        let condvar = Arc::new(Condvar::new());
+        let generation = condvar.__generation();
+
+        let notifier = {
+            let condvar = condvar.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                condvar.notify_all();
+            })
+        };
+
+        condvar.__wait(generation);
+        assert_eq!(condvar.__generation(), generation + 1);
+        notifier.join().unwrap();
+    }
+}
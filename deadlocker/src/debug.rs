@@ -0,0 +1,30 @@
+//! Support for the `Debug` implementation generated for structs marked with `#[locker(debug)]`
+//!
+//! Each field is only attempted to be locked, so formatting a struct never blocks, even while its
+//! locks are held by the formatting thread itself. The attempts are made through
+//! [TryAcquire](crate::stats::TryAcquire), as when detecting contention.
+
+use std::{fmt, ops::Deref};
+
+/// The outcome of attempting to lock a field without blocking
+pub enum Attempt<G> {
+    /// The lock was acquired, formatted as the locked value
+    Acquired(G),
+    /// The lock is held elsewhere, formatted as `<locked>`
+    Locked,
+    /// The lock is poisoned, formatted as `<poisoned>`
+    Poisoned,
+}
+
+impl<G: Deref> fmt::Debug for Attempt<G>
+where
+    G::Target: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Attempt::Acquired(guard) => fmt::Debug::fmt(&**guard, f),
+            Attempt::Locked => f.write_str("<locked>"),
+            Attempt::Poisoned => f.write_str("<poisoned>"),
+        }
+    }
+}
@@ -0,0 +1,186 @@
+use std::ops::{DerefMut, Index, IndexMut};
+
+use crate::guard::Dirty;
+
+/// The guard of a single locked element
+pub type Guard<'a, T> = Box<Dirty<dyn DerefMut<Target = T> + 'a>>;
+
+/// The guards of the elements locked in a `collection` field, ordered by their keys
+///
+/// For `Vec` fields the keys are the selected indices
+pub struct Elements<'a, K, T: ?Sized> {
+    elements: Vec<(K, Guard<'a, T>)>,
+}
+
+impl<'a, K: Ord, T: ?Sized> Elements<'a, K, T> {
+    /// Creates a new [Elements] from guards already sorted and deduplicated by key
+    pub fn new(elements: Vec<(K, Guard<'a, T>)>) -> Self {
+        debug_assert!(elements.windows(2).all(|w| w[0].0 < w[1].0));
+        Elements { elements }
+    }
+
+    /// Returns a reference to the element locked under `key`, if any
+    pub fn get(&self, key: &K) -> Option<&T> {
+        let position = self.position(key)?;
+        Some(&**self.elements[position].1)
+    }
+
+    /// Returns a mutable reference to the element locked under `key`, if any
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut T> {
+        let position = self.position(key)?;
+        Some(&mut **self.elements[position].1)
+    }
+
+    /// Returns whether an element is locked under `key`
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.position(key).is_some()
+    }
+
+    /// Returns the keys of the locked elements in ascending order
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.elements.iter().map(|(key, _)| key)
+    }
+
+    /// Iterates over the locked elements in ascending order of their keys
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &T)> {
+        self.elements
+            .iter()
+            .map(|(key, guard)| (key, &***guard))
+    }
+
+    /// Mutably iterates over the locked elements in ascending order of their keys
+    pub fn iter_mut(&mut self) -> IterMut<'_, 'a, K, T> {
+        IterMut {
+            inner: self.elements.iter_mut(),
+        }
+    }
+
+    /// Returns whether any of the locked elements was mutably accessed
+    pub fn is_dirty(&self) -> bool {
+        self.elements.iter().any(|(_, guard)| Dirty::is_dirty(&**guard))
+    }
+
+    /// Returns the keys of the locked elements which were mutably accessed, in ascending order
+    pub fn dirty_keys(&self) -> impl Iterator<Item = &K> {
+        self.elements
+            .iter()
+            .filter(|(_, guard)| Dirty::is_dirty(&**guard))
+            .map(|(key, _)| key)
+    }
+
+    /// Marks the locked elements under `keys` as mutably accessed, ignoring keys which are not locked
+    pub fn mark_dirty(&mut self, keys: &[K]) {
+        for key in keys {
+            if let Some(position) = self.position(key) {
+                Dirty::mark_dirty(&mut *self.elements[position].1);
+            }
+        }
+    }
+
+    /// Returns the number of locked elements
+    pub fn len(&self) -> usize {
+        self.elements.len()
+    }
+
+    /// Returns whether no elements are locked
+    pub fn is_empty(&self) -> bool {
+        self.elements.is_empty()
+    }
+
+    fn position(&self, key: &K) -> Option<usize> {
+        self.elements
+            .binary_search_by(|(existing, _)| existing.cmp(key))
+            .ok()
+    }
+}
+
+impl<'a, K: Ord, T: ?Sized> Index<K> for Elements<'a, K, T> {
+    type Output = T;
+
+    fn index(&self, key: K) -> &Self::Output {
+        self.get(&key).expect("Element was not locked")
+    }
+}
+
+impl<'a, K: Ord, T: ?Sized> IndexMut<K> for Elements<'a, K, T> {
+    fn index_mut(&mut self, key: K) -> &mut Self::Output {
+        self.get_mut(&key).expect("Element was not locked")
+    }
+}
+
+/// Mutable iterator over the locked elements of an [Elements]
+pub struct IterMut<'s, 'a, K, T: ?Sized> {
+    inner: std::slice::IterMut<'s, (K, Guard<'a, T>)>,
+}
+
+impl<'s, 'a, K, T: ?Sized> Iterator for IterMut<'s, 'a, K, T> {
+    type Item = (&'s K, &'s mut T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(key, guard)| (&*key, &mut ***guard))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn elements<'a>(values: &'a mut [(usize, u32)]) -> Elements<'a, usize, u32> {
+        Elements::new(
+            values
+                .iter_mut()
+                .map(|(key, value)| (*key, Box::new(Dirty::new(value)) as Guard<'a, u32>))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn get_finds_locked_keys() {
+        let mut values = [(1, 10), (4, 40), (7, 70), (9, 90)];
+        let elements = elements(&mut values);
+
+        assert_eq!(elements.get(&1), Some(&10));
+        assert_eq!(elements.get(&7), Some(&70));
+        assert_eq!(elements.get(&9), Some(&90));
+        assert_eq!(elements.get(&0), None);
+        assert_eq!(elements.get(&5), None);
+        assert_eq!(elements.get(&10), None);
+        assert!(elements.contains_key(&4));
+        assert!(!elements.contains_key(&3));
+        assert_eq!(elements[4], 40);
+    }
+
+    #[test]
+    fn get_mut_marks_only_accessed_keys_dirty() {
+        let mut values = [(2, 20), (3, 30), (8, 80)];
+        let mut elements = elements(&mut values);
+
+        *elements.get_mut(&8).unwrap() += 1;
+        elements[2] += 1;
+        assert_eq!(elements.get_mut(&5), None);
+        assert_eq!(elements.dirty_keys().collect::<Vec<_>>(), [&2, &8]);
+
+        drop(elements);
+        assert_eq!(values, [(2, 21), (3, 30), (8, 81)]);
+    }
+
+    #[test]
+    fn mark_dirty_ignores_unlocked_keys() {
+        let mut values = [(1, 10), (5, 50)];
+        let mut elements = elements(&mut values);
+
+        assert!(!elements.is_dirty());
+        elements.mark_dirty(&[5, 6]);
+        assert!(elements.is_dirty());
+        assert_eq!(elements.dirty_keys().collect::<Vec<_>>(), [&5]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Element was not locked")]
+    fn index_panics_for_unlocked_key() {
+        let mut values = [(1, 10)];
+        let elements = elements(&mut values);
+
+        let _ = elements[2];
+    }
+}
@@ -0,0 +1,32 @@
+use std::fmt::Display;
+
+/// Errors raised by the generated lockers themselves, as opposed to by the underlying locks
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum LockError {
+    /// An index selected for a `collection` field was out of bounds
+    IndexOutOfBounds {
+        field: &'static str,
+        index: usize,
+        len: usize,
+    },
+    /// A key selected for a `keyed` field was not present, formatted through its `Debug`
+    /// implementation
+    MissingKey { field: &'static str, key: String },
+}
+
+impl Display for LockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LockError::IndexOutOfBounds { field, index, len } => write!(
+                f,
+                "index {index} is out of bounds for field `{field}` of length {len}"
+            ),
+            LockError::MissingKey { field, key } => {
+                write!(f, "key {key} is not present in field `{field}`")
+            }
+        }
+    }
+}
+
+impl std::error::Error for LockError {}
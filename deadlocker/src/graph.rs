@@ -0,0 +1,262 @@
+//! Global graph of the order in which locks are acquired, reporting potential deadlocks
+//!
+//! Whenever a lock is acquired while others are held, an edge from each held lock to the acquired
+//! one is recorded, across all threads. The first time an edge closes a cycle, the cycle is
+//! reported, even if no deadlock actually occurred, as the threads involved could deadlock given
+//! less fortunate timing. Reports are logged through [log::error], unless another reporter is
+//! installed using [set_reporter]. Acquiring a field while holding the same field of another
+//! instance is reported as a cycle of a single edge, unless the instances are locked through
+//! [lock_pair](crate::lock_pair) or [lock_all](crate::lock_all).
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Display,
+    panic::Location,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use crate::{
+    held::{Acquiring, Class, Held},
+    LockFieldInfo,
+};
+
+/// The first observed acquisition of one lock while another was held
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Edge {
+    pub held: LockFieldInfo,
+    pub held_at: &'static Location<'static>,
+    pub acquired: LockFieldInfo,
+    pub acquired_at: &'static Location<'static>,
+}
+
+/// A cycle of [Edge]s, where each lock is acquired while holding the previous one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cycle {
+    pub edges: Vec<Edge>,
+}
+
+impl Display for Cycle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Potential deadlock, the following acquisitions form a cycle:")?;
+        for edge in &self.edges {
+            writeln!(
+                f,
+                "  {} acquired at {} while holding {} acquired at {}",
+                edge.acquired, edge.acquired_at, edge.held, edge.held_at
+            )?;
+        }
+        Ok(())
+    }
+}
+
+type Reporter = Arc<dyn Fn(&Cycle) + Send + Sync>;
+
+/// The successors of each lock, along with the edges leading to them
+type Graph = HashMap<Class, Vec<(Class, Edge)>>;
+
+static GRAPH: Mutex<Option<Graph>> = Mutex::new(None);
+static REPORTER: Mutex<Option<Reporter>> = Mutex::new(None);
+
+/// Installs the function called with each detected [Cycle], replacing the default of logging it
+/// through [log::error]
+pub fn set_reporter(reporter: impl Fn(&Cycle) + Send + Sync + 'static) {
+    *REPORTER.lock().unwrap_or_else(PoisonError::into_inner) = Some(Arc::new(reporter));
+}
+
+/// Returns all edges recorded so far
+pub fn edges() -> Vec<Edge> {
+    GRAPH
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .iter()
+        .flat_map(HashMap::values)
+        .flatten()
+        .map(|(_, edge)| *edge)
+        .collect()
+}
+
+/// Records an edge from each of the `held` locks to the lock being acquired, returning the cycles
+/// closed by the new edges
+///
+/// Acquiring a field while holding the same field of another instance records an edge from the
+/// field to itself, see [Acquiring::recurses]
+pub(crate) fn record(held: &[Held], acquiring: &Acquiring) -> Vec<Cycle> {
+    let (class, site) = (&acquiring.class, acquiring.site);
+    let mut graph = GRAPH.lock().unwrap_or_else(PoisonError::into_inner);
+    let graph = graph.get_or_insert_with(HashMap::new);
+
+    let mut cycles = Vec::new();
+    for outer in held
+        .iter()
+        .filter(|h| h.class != *class || acquiring.recurses(h))
+    {
+        let successors = graph.entry(outer.class).or_default();
+        if successors.iter().any(|(successor, _)| successor == class) {
+            continue;
+        }
+
+        let edge = Edge {
+            held: outer.class.0,
+            held_at: outer.site,
+            acquired: class.0,
+            acquired_at: site,
+        };
+        successors.push((*class, edge));
+
+        if let Some(mut path) = find_path(graph, class, &outer.class) {
+            path.insert(0, edge);
+            cycles.push(Cycle { edges: path });
+        }
+    }
+
+    cycles
+}
+
+/// Passes a [Cycle] to the installed reporter
+pub(crate) fn report(cycle: &Cycle) {
+    let reporter = REPORTER
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone();
+
+    match reporter {
+        Some(reporter) => reporter(cycle),
+        None => log::error!("{}", cycle),
+    }
+}
+
+/// Returns the edges of the shortest path from `from` to `to`, if any
+fn find_path(graph: &Graph, from: &Class, to: &Class) -> Option<Vec<Edge>> {
+    let mut predecessors: HashMap<Class, (Class, Edge)> = HashMap::new();
+    let mut queue = VecDeque::from([*from]);
+
+    while let Some(current) = queue.pop_front() {
+        if current == *to {
+            let mut path = Vec::new();
+            let mut node = current;
+            while node != *from {
+                let (previous, edge) = predecessors[&node];
+                path.push(edge);
+                node = previous;
+            }
+            path.reverse();
+            return Some(path);
+        }
+
+        for (successor, edge) in graph.get(&current).into_iter().flatten() {
+            if *successor != *from && !predecessors.contains_key(successor) {
+                predecessors.insert(*successor, (current, *edge));
+                queue.push_back(*successor);
+            }
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic::Location;
+
+    use super::*;
+    use crate::LockKind;
+
+    /// Returns the class of a field, with a struct name unique to each test as the graph is shared
+    /// by all tests
+    fn class(struct_name: &'static str, field_name: &'static str) -> Class {
+        let info = LockFieldInfo {
+            module_path: module_path!(),
+            struct_name,
+            field_name,
+            rank: 0,
+            inner_type: "u32",
+            is_async: false,
+            is_result: true,
+            kind: LockKind::Single,
+        };
+        (info, 0)
+    }
+
+    fn edge(held: &Class, acquired: &Class) -> Edge {
+        Edge {
+            held: held.0,
+            held_at: Location::caller(),
+            acquired: acquired.0,
+            acquired_at: Location::caller(),
+        }
+    }
+
+    #[test]
+    fn find_path_returns_shortest_path() {
+        let (a, b, c) = (class("Path", "a"), class("Path", "b"), class("Path", "c"));
+        let mut graph = Graph::new();
+        graph.insert(a, vec![(b, edge(&a, &b)), (c, edge(&a, &c))]);
+        graph.insert(b, vec![(c, edge(&b, &c))]);
+
+        assert_eq!(find_path(&graph, &a, &c), Some(vec![edge(&a, &c)]));
+        assert_eq!(find_path(&graph, &b, &c), Some(vec![edge(&b, &c)]));
+        assert_eq!(find_path(&graph, &c, &a), None);
+    }
+
+    #[test]
+    fn find_path_follows_edges_in_order() {
+        let (a, b, c) = (class("Chain", "a"), class("Chain", "b"), class("Chain", "c"));
+        let mut graph = Graph::new();
+        graph.insert(a, vec![(b, edge(&a, &b))]);
+        graph.insert(b, vec![(c, edge(&b, &c))]);
+
+        assert_eq!(
+            find_path(&graph, &a, &c),
+            Some(vec![edge(&a, &b), edge(&b, &c)])
+        );
+    }
+
+    #[test]
+    fn record_reports_inverted_pair_once() {
+        let (a, b) = (class("Inverted", "a"), class("Inverted", "b"));
+        let site = Location::caller();
+
+        let held_a = [Held::new(a, 0)];
+        assert!(record(&held_a, &Acquiring::new(b, site, 0, false)).is_empty());
+
+        let held_b = [Held::new(b, 1)];
+        let cycles = record(&held_b, &Acquiring::new(a, site, 1, false));
+        assert_eq!(cycles.len(), 1);
+        let acquired: Vec<_> = cycles[0].edges.iter().map(|edge| edge.acquired).collect();
+        assert_eq!(acquired, vec![a.0, b.0]);
+
+        assert!(record(&held_b, &Acquiring::new(a, site, 2, false)).is_empty());
+    }
+
+    #[test]
+    fn record_reports_cycle_across_three_locks() {
+        let (a, b, c) = (class("Three", "a"), class("Three", "b"), class("Three", "c"));
+        let site = Location::caller();
+
+        assert!(record(&[Held::new(a, 0)], &Acquiring::new(b, site, 0, false)).is_empty());
+        assert!(record(&[Held::new(b, 1)], &Acquiring::new(c, site, 1, false)).is_empty());
+        let cycles = record(&[Held::new(c, 2)], &Acquiring::new(a, site, 2, false));
+        assert_eq!(cycles.len(), 1);
+        assert_eq!(cycles[0].edges.len(), 3);
+    }
+
+    #[test]
+    fn record_reports_same_field_of_two_instances() {
+        let a = class("Recursive", "a");
+        let site = Location::caller();
+
+        let cycles = record(&[Held::new(a, 0)], &Acquiring::new(a, site, 1, false));
+        assert_eq!(cycles.len(), 1);
+        assert_eq!(cycles[0].edges.len(), 1);
+        assert_eq!((cycles[0].edges[0].held, cycles[0].edges[0].acquired), (a.0, a.0));
+    }
+
+    #[test]
+    fn record_ignores_same_field_within_chain_or_scope() {
+        let a = class("Ordered", "a");
+        let site = Location::caller();
+
+        assert!(record(&[Held::new(a, 0)], &Acquiring::new(a, site, 0, false)).is_empty());
+        assert!(record(&[Held::new(a, 0)], &Acquiring::new(a, site, 1, true)).is_empty());
+    }
+}
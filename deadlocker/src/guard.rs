@@ -0,0 +1,107 @@
+use std::{
+    ops::{Deref, DerefMut},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+/// A guard carrying a token which is dropped right after the guard itself
+///
+/// Used by the derive macro to attach bookkeeping to the release of a lock. Multiple tokens may be
+/// carried as a tuple, in which case they are dropped in order.
+pub struct Tracked<G, T> {
+    guard: G,
+    token: T,
+}
+
+impl<G, T> Tracked<G, T> {
+    pub fn new(guard: G, token: T) -> Self {
+        Tracked { guard, token }
+    }
+
+    /// Returns the token carried by the guard
+    pub fn token(&self) -> &T {
+        &self.token
+    }
+}
+
+impl<G: Deref, T> Deref for Tracked<G, T> {
+    type Target = G::Target;
+
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+impl<G: DerefMut, T> DerefMut for Tracked<G, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.guard
+    }
+}
+
+/// A guard recording whether it was mutably dereferenced, wrapping every guard of a generated state
+///
+/// The guard is the last field, so that boxed guards may be unsized into
+/// `Box<Dirty<dyn DerefMut<Target = T>>>`.
+pub struct Dirty<G: ?Sized> {
+    dirty: bool,
+    flag: Option<Arc<AtomicBool>>,
+    guard: G,
+}
+
+impl<G> Dirty<G> {
+    pub fn new(guard: G) -> Self {
+        Dirty {
+            dirty: false,
+            flag: None,
+            guard,
+        }
+    }
+
+    /// Creates a guard which also raises `flag` when mutably dereferenced, such as for a token
+    /// dropped along with the guard
+    pub fn shared(guard: G, flag: Arc<AtomicBool>) -> Self {
+        Dirty {
+            dirty: false,
+            flag: Some(flag),
+            guard,
+        }
+    }
+}
+
+impl<G: ?Sized> Dirty<G> {
+    /// Returns whether the guard was mutably dereferenced
+    ///
+    /// This is an associated function, so as not to shadow methods of the guarded value
+    pub fn is_dirty(this: &Self) -> bool {
+        this.dirty
+    }
+
+    /// Marks the guard as mutably dereferenced, such as when reacquired after having been
+    ///
+    /// Unlike dereferencing, this does not raise the shared flag
+    pub fn mark_dirty(this: &mut Self) {
+        this.dirty = true;
+    }
+}
+
+impl<G: Deref + ?Sized> Deref for Dirty<G> {
+    type Target = G::Target;
+
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+impl<G: DerefMut + ?Sized> DerefMut for Dirty<G> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        if !self.dirty {
+            self.dirty = true;
+            if let Some(flag) = &self.flag {
+                flag.store(true, Ordering::Relaxed);
+            }
+        }
+        &mut self.guard
+    }
+}
@@ -0,0 +1,179 @@
+//! Tracking of the locks held by each thread, feeding the lock order validation and the lock graph
+//!
+//! Only synchronous lock chains are tracked, as tasks may move between threads while holding
+//! asynchronous locks.
+
+use std::{
+    cell::{Cell, RefCell},
+    panic::Location,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::LockFieldInfo;
+
+pub(crate) type Site = &'static Location<'static>;
+
+/// A field, along with the position of its instance when locking several instances at once
+///
+/// Locks acquired through [lock_pair](crate::lock_pair) and [lock_all](crate::lock_all) are
+/// distinguished by position, as the same field is expected to be locked on several instances.
+pub(crate) type Class = (LockFieldInfo, usize);
+
+pub(crate) struct Held {
+    id: u64,
+    pub(crate) class: Class,
+    pub(crate) site: Site,
+    chain: u64,
+}
+
+/// A lock about to be acquired
+pub(crate) struct Acquiring {
+    pub(crate) class: Class,
+    pub(crate) site: Site,
+    chain: u64,
+    scoped: bool,
+}
+
+impl Acquiring {
+    /// Returns whether acquiring the lock while holding `held` nests two locks of the same class,
+    /// such as when locking the same field of two instances one after the other
+    ///
+    /// Locks of the same class acquired by a single `lock` call, such as the elements of a
+    /// collection, are ordered by the derive macro, as are the instances locked through
+    /// [lock_pair](crate::lock_pair) and [lock_all](crate::lock_all).
+    pub(crate) fn recurses(&self, held: &Held) -> bool {
+        held.class == self.class && held.chain != self.chain && !self.scoped
+    }
+}
+
+#[cfg(test)]
+impl Held {
+    pub(crate) fn new(class: Class, chain: u64) -> Self {
+        Held {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            class,
+            site: std::panic::Location::caller(),
+            chain,
+        }
+    }
+}
+
+#[cfg(test)]
+impl Acquiring {
+    pub(crate) fn new(class: Class, site: Site, chain: u64, scoped: bool) -> Self {
+        Acquiring {
+            class,
+            site,
+            chain,
+            scoped,
+        }
+    }
+}
+
+thread_local! {
+    static HELD: RefCell<Vec<Held>> = const { RefCell::new(Vec::new()) };
+    static INSTANCE: Cell<Option<usize>> = const { Cell::new(None) };
+    static CHAIN: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Releases the acquisition it was returned for when dropped
+pub struct HeldToken {
+    id: u64,
+}
+
+impl Drop for HeldToken {
+    fn drop(&mut self) {
+        let _ = HELD.try_with(|held| {
+            let mut held = held.borrow_mut();
+            if let Some(position) = held.iter().rposition(|h| h.id == self.id) {
+                held.remove(position);
+            }
+        });
+    }
+}
+
+/// Records the acquisition of a lock at `site`, to be called right before acquiring it
+///
+/// Panics if the `debug-order-check` feature is enabled and the acquisition inverts the order of a
+/// previously observed pair of locks
+pub fn acquire(field: &LockFieldInfo, site: Site) -> HeldToken {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let instance = INSTANCE.with(Cell::get);
+    let acquiring = Acquiring {
+        class: (*field, instance.unwrap_or(0)),
+        site,
+        chain: CHAIN.with(Cell::get).unwrap_or(id),
+        scoped: instance.is_some(),
+    };
+
+    #[cfg(feature = "debug-order-check")]
+    {
+        let inversion = HELD.with(|held| crate::order::check(&held.borrow(), &acquiring));
+        if let Some(message) = inversion {
+            panic!("{}", message);
+        }
+    }
+
+    #[cfg(feature = "lock-graph")]
+    {
+        let cycles = HELD.with(|held| crate::graph::record(&held.borrow(), &acquiring));
+        for cycle in &cycles {
+            crate::graph::report(cycle);
+        }
+    }
+
+    HELD.with(|held| {
+        held.borrow_mut().push(Held {
+            id,
+            class: acquiring.class,
+            site,
+            chain: acquiring.chain,
+        })
+    });
+    HeldToken { id }
+}
+
+/// Groups the locks acquired while it lives as acquired by a single `lock` call, including the
+/// locks of flattened fields acquired by nested `lock` calls
+pub struct ChainScope {
+    previous: Option<u64>,
+}
+
+impl ChainScope {
+    pub fn enter() -> Self {
+        let previous = CHAIN.with(Cell::get);
+        if previous.is_none() {
+            let chain = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+            CHAIN.with(|current| current.set(Some(chain)));
+        }
+        ChainScope { previous }
+    }
+}
+
+impl Drop for ChainScope {
+    fn drop(&mut self) {
+        let _ = CHAIN.try_with(|current| current.set(self.previous));
+    }
+}
+
+/// Tracks the locks acquired while it lives as belonging to the instance at a given position in
+/// the order of the instances being locked
+pub(crate) struct InstanceScope {
+    previous: Option<usize>,
+}
+
+impl InstanceScope {
+    pub(crate) fn enter(position: usize) -> Self {
+        InstanceScope {
+            previous: INSTANCE.with(|instance| instance.replace(Some(position))),
+        }
+    }
+}
+
+impl Drop for InstanceScope {
+    fn drop(&mut self) {
+        let _ = INSTANCE.try_with(|instance| instance.set(self.previous));
+    }
+}
@@ -0,0 +1,123 @@
+//! Warnings about locks held for longer than the threshold set through `#[locker(warn_hold)]`
+//!
+//! Long holds are logged through [log::warn], unless a hook is installed with [set_hook].
+
+use std::{
+    fmt::Display,
+    panic::Location,
+    sync::{PoisonError, RwLock},
+    time::{Duration, Instant},
+};
+
+use crate::LockFieldInfo;
+
+/// A lock which was held for longer than its threshold
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LongHold {
+    pub field: LockFieldInfo,
+    pub held: Duration,
+    pub threshold: Duration,
+    pub location: &'static Location<'static>,
+}
+
+impl Display for LongHold {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} acquired at {} was held for {:?}, exceeding {:?}",
+            self.field, self.location, self.held, self.threshold
+        )
+    }
+}
+
+type Hook = Box<dyn Fn(&LongHold) + Send + Sync>;
+
+static HOOK: RwLock<Option<Hook>> = RwLock::new(None);
+
+/// Installs the function called whenever a lock is held for longer than its threshold, replacing
+/// the default of logging a warning
+pub fn set_hook(hook: impl Fn(&LongHold) + Send + Sync + 'static) {
+    *HOOK.write().unwrap_or_else(PoisonError::into_inner) = Some(Box::new(hook));
+}
+
+fn report(long_hold: &LongHold) {
+    match &*HOOK.read().unwrap_or_else(PoisonError::into_inner) {
+        Some(hook) => hook(long_hold),
+        None => log::warn!("{}", long_hold),
+    }
+}
+
+/// Starts timing the hold of an acquired lock, to be called right after acquiring it
+pub fn watch(
+    field: &'static LockFieldInfo,
+    threshold: Duration,
+    location: &'static Location<'static>,
+) -> HoldToken {
+    HoldToken {
+        field,
+        threshold,
+        location,
+        acquired: Instant::now(),
+    }
+}
+
+/// The hold of a single lock, reporting it when dropped if held for longer than its threshold
+pub struct HoldToken {
+    field: &'static LockFieldInfo,
+    threshold: Duration,
+    location: &'static Location<'static>,
+    acquired: Instant,
+}
+
+impl Drop for HoldToken {
+    fn drop(&mut self) {
+        let held = self.acquired.elapsed();
+        if held > self.threshold {
+            report(&LongHold {
+                field: *self.field,
+                held,
+                threshold: self.threshold,
+                location: self.location,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Mutex, thread::sleep};
+
+    use super::*;
+    use crate::LockKind;
+
+    static CONFIG: LockFieldInfo = LockFieldInfo {
+        module_path: module_path!(),
+        struct_name: "Held",
+        field_name: "config",
+        rank: 0,
+        inner_type: "u32",
+        is_async: false,
+        is_result: true,
+        kind: LockKind::Single,
+    };
+
+    #[test]
+    fn only_holds_exceeding_the_threshold_are_reported() {
+        static REPORTED: Mutex<Vec<LongHold>> = Mutex::new(Vec::new());
+        set_hook(|long_hold| REPORTED.lock().unwrap().push(*long_hold));
+
+        let location = Location::caller();
+        drop(watch(&CONFIG, Duration::from_secs(60), location));
+        let long = watch(&CONFIG, Duration::from_millis(1), location);
+        sleep(Duration::from_millis(10));
+        drop(long);
+
+        let reported = REPORTED.lock().unwrap();
+        assert_eq!(reported.len(), 1);
+        assert_eq!(reported[0].field, CONFIG);
+        assert_eq!(reported[0].threshold, Duration::from_millis(1));
+        assert!(reported[0].held >= Duration::from_millis(10));
+        assert_eq!(reported[0].location, location);
+        assert!(reported[0].to_string().ends_with("exceeding 1ms"));
+    }
+}
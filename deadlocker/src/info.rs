@@ -0,0 +1,152 @@
+use std::fmt::Display;
+
+/// Static description of a field locked by a generated locker
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LockFieldInfo {
+    /// The module the struct is declared in, as given by `module_path!()`
+    pub module_path: &'static str,
+    pub struct_name: &'static str,
+    pub field_name: &'static str,
+    /// The position of the field in the lock order of the struct
+    pub rank: usize,
+    /// The type behind the lock, or the type of the nested struct for flattened fields
+    pub inner_type: &'static str,
+    pub is_async: bool,
+    pub is_result: bool,
+    pub kind: LockKind,
+}
+
+impl LockFieldInfo {
+    /// Returns whether `names` are all fields of `fields`, listed in the same order, to check the
+    /// fields listed in `#[locker(flatten(...))]` at compile time
+    ///
+    /// ```compile_fail
+    /// use deadlocker::Locker;
+    /// use std::sync::{Arc, Mutex};
+    ///
+    /// #[derive(Locker)]
+    /// pub struct Session {
+    ///     #[result]
+    ///     pub user: Arc<Mutex<String>>,
+    ///     #[result]
+    ///     pub token: Arc<Mutex<u64>>,
+    /// }
+    ///
+    /// #[derive(Locker)]
+    /// pub struct AppState {
+    ///     #[locker(flatten(token, user))]
+    ///     pub session: Session,
+    /// }
+    /// ```
+    #[doc(hidden)]
+    pub const fn __in_lock_order(fields: &[LockFieldInfo], names: &[&str]) -> bool {
+        let mut position = 0;
+        let mut name = 0;
+        while name < names.len() {
+            while position < fields.len()
+                && !str_equals(fields[position].field_name, names[name])
+            {
+                position += 1;
+            }
+            if position == fields.len() {
+                return false;
+            }
+            position += 1;
+            name += 1;
+        }
+        true
+    }
+}
+
+const fn str_equals(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut index = 0;
+    while index < a.len() {
+        if a[index] != b[index] {
+            return false;
+        }
+        index += 1;
+    }
+    true
+}
+
+/// The way a field is locked by a generated locker
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum LockKind {
+    /// A single lock
+    Single,
+    /// A nested struct, see `#[locker(flatten(...))]`
+    Flattened,
+    /// Selected elements of a `Vec` of locks, see `#[locker(collection)]`
+    Collection,
+    /// Selected entries of a map of locks, see `#[locker(keyed)]`
+    Keyed,
+}
+
+/// Static description of the fields of a struct deriving `Locker`
+pub trait LockerMeta {
+    /// The locked fields of the struct, in lock order
+    const LOCK_FIELDS: &'static [LockFieldInfo];
+}
+
+impl Display for LockFieldInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.struct_name, self.field_name)
+    }
+}
+
+/// Static description of a set of fields locked together by a single generated `lock` method
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LockSetInfo {
+    /// The module the struct is declared in, as given by `module_path!()`
+    pub module_path: &'static str,
+    pub struct_name: &'static str,
+    /// The names of the locked fields, in lock order
+    pub field_names: &'static [&'static str],
+}
+
+impl Display for LockSetInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{{{}}}", self.struct_name, self.field_names.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(field_name: &'static str) -> LockFieldInfo {
+        LockFieldInfo {
+            module_path: module_path!(),
+            struct_name: "Session",
+            field_name,
+            rank: 0,
+            inner_type: "u32",
+            is_async: false,
+            is_result: false,
+            kind: LockKind::Single,
+        }
+    }
+
+    #[test]
+    fn fields_in_lock_order_pass() {
+        let fields = [info("user"), info("token"), info("expiry")];
+
+        assert!(LockFieldInfo::__in_lock_order(&fields, &["user", "token", "expiry"]));
+        assert!(LockFieldInfo::__in_lock_order(&fields, &["user", "expiry"]));
+        assert!(LockFieldInfo::__in_lock_order(&fields, &["token"]));
+    }
+
+    #[test]
+    fn fields_out_of_order_or_unknown_fail() {
+        let fields = [info("user"), info("token"), info("expiry")];
+
+        assert!(!LockFieldInfo::__in_lock_order(&fields, &["token", "user"]));
+        assert!(!LockFieldInfo::__in_lock_order(&fields, &["user", "user"]));
+        assert!(!LockFieldInfo::__in_lock_order(&fields, &["user", "tokens"]));
+    }
+}
@@ -0,0 +1,96 @@
+use std::cmp::Ordering;
+
+use crate::{Lock, Locker};
+
+#[cfg(any(feature = "debug-order-check", feature = "lock-graph"))]
+use crate::held::InstanceScope;
+
+/// Stand-in for the scope used when tracking held locks
+#[cfg(not(any(feature = "debug-order-check", feature = "lock-graph")))]
+struct InstanceScope;
+
+#[cfg(not(any(feature = "debug-order-check", feature = "lock-graph")))]
+impl InstanceScope {
+    fn enter(_position: usize) -> Self {
+        InstanceScope
+    }
+}
+
+/// Provides the key used to order instances of the same struct when locking several at once
+///
+/// The derive macro implements this using the field marked with `#[locker(id)]`, or, lacking such
+/// a field, the address of the lock shared through the first field if it is an `Arc` or `Rc`
+pub trait LockInstance {
+    type Key: Ord;
+    fn instance_key(&self) -> Self::Key;
+}
+
+/// Locks the same selection of fields on two instances of a struct, in the order of their
+/// [LockInstance] keys, returning the guards in the order the instances were given
+///
+/// Panics if both instances have the same key, as locking them would deadlock
+#[track_caller]
+pub fn lock_pair<'a, T, F, B>(
+    first: &'a mut T,
+    second: &'a mut T,
+    select: F,
+) -> (B::Output, B::Output)
+where
+    T: Locker<'a> + LockInstance,
+    F: Fn(T::LockBuilder) -> B,
+    B: Lock,
+{
+    match first.instance_key().cmp(&second.instance_key()) {
+        Ordering::Less => {
+            let first = {
+                let _scope = InstanceScope::enter(0);
+                select(first.locker()).lock()
+            };
+            let _scope = InstanceScope::enter(1);
+            let second = select(second.locker()).lock();
+            (first, second)
+        }
+        Ordering::Greater => {
+            let second = {
+                let _scope = InstanceScope::enter(0);
+                select(second.locker()).lock()
+            };
+            let _scope = InstanceScope::enter(1);
+            let first = select(first.locker()).lock();
+            (first, second)
+        }
+        Ordering::Equal => panic!("Attempted to lock the same instance twice"),
+    }
+}
+
+/// Locks the same selection of fields on any number of instances of a struct, in the order of
+/// their [LockInstance] keys, returning the guards in the order the instances were given
+///
+/// Panics if any two instances have the same key, as locking them would deadlock
+#[track_caller]
+pub fn lock_all<'a, T, I, F, B>(instances: I, mut select: F) -> Vec<B::Output>
+where
+    T: Locker<'a> + LockInstance + 'a,
+    I: IntoIterator<Item = &'a mut T>,
+    F: FnMut(T::LockBuilder) -> B,
+    B: Lock,
+{
+    let mut keyed: Vec<(T::Key, usize, &'a mut T)> = instances
+        .into_iter()
+        .enumerate()
+        .map(|(position, instance)| (instance.instance_key(), position, instance))
+        .collect();
+    keyed.sort_by(|a, b| a.0.cmp(&b.0));
+
+    if keyed.windows(2).any(|w| w[0].0 == w[1].0) {
+        panic!("Attempted to lock the same instance twice");
+    }
+
+    let mut outputs: Vec<Option<B::Output>> = keyed.iter().map(|_| None).collect();
+    for (rank, (_, position, instance)) in keyed.into_iter().enumerate() {
+        let _scope = InstanceScope::enter(rank);
+        outputs[position] = Some(select(instance.locker()).lock());
+    }
+
+    outputs.into_iter().flatten().collect()
+}
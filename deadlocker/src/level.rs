@@ -0,0 +1,188 @@
+//! Compile time lock levels, set through `#[locker(level = N)]`
+//!
+//! Locking a struct with a level requires a [LockToken] of a strictly lower level, and yields a
+//! token of the level of its highest locked field. The token passed in remains mutably borrowed
+//! for as long as the locks or the yielded token are held, so that only locks of higher levels
+//! may be acquired meanwhile. Acquiring locks in the wrong order across structs thereby fails to
+//! compile. A thread starts out with the token returned by [LockToken::unlocked].
+//!
+//! Locking a struct of a lower level while holding the token yielded by a struct of a higher
+//! level fails to compile:
+//!
+//! ```compile_fail
+//! use deadlocker::{LockToken, Locker};
+//! use std::sync::{Arc, Mutex};
+//!
+//! #[derive(Locker)]
+//! #[locker(level = 0)]
+//! pub struct Config {
+//!     #[result]
+//!     pub max_users: Arc<Mutex<usize>>,
+//! }
+//!
+//! #[derive(Locker)]
+//! #[locker(level = 1)]
+//! pub struct Sessions {
+//!     #[result]
+//!     pub users: Arc<Mutex<Vec<String>>>,
+//! }
+//!
+//! let mut config = Config { max_users: Arc::new(Mutex::new(2)) };
+//! let mut sessions = Sessions { users: Arc::new(Mutex::new(Vec::new())) };
+//! let mut token = LockToken::unlocked();
+//!
+//! let (_sessions, mut token) = sessions.locker().users().lock(&mut token).unwrap();
+//! let (_config, _) = config.locker().max_users().lock(&mut token).unwrap();
+//! ```
+//!
+//! Tokens can not be sent to another thread, which would otherwise hold a second token asserting
+//! that no locks are held:
+//!
+//! ```compile_fail
+//! use deadlocker::LockToken;
+//!
+//! let token = std::thread::spawn(LockToken::unlocked).join().unwrap();
+//! ```
+//!
+//! Tokens can only be obtained through [LockToken::unlocked] or unsafely, so a token of a higher
+//! level can not be made up to skip the order:
+//!
+//! ```compile_fail
+//! use deadlocker::{level::L5, LockToken};
+//!
+//! let token: LockToken<'static, L5> = LockToken::unlocked();
+//! ```
+//!
+//! The token passed to `lock` stays borrowed while the yielded token is alive, so it can not be
+//! used to lock another struct meanwhile:
+//!
+//! ```compile_fail
+//! use deadlocker::{LockToken, Locker};
+//! use std::sync::{Arc, Mutex};
+//!
+//! #[derive(Locker)]
+//! #[locker(level = 1)]
+//! pub struct Sessions {
+//!     #[result]
+//!     pub users: Arc<Mutex<Vec<String>>>,
+//! }
+//!
+//! let mut first = Sessions { users: Arc::new(Mutex::new(Vec::new())) };
+//! let mut second = Sessions { users: Arc::new(Mutex::new(Vec::new())) };
+//! let mut token = LockToken::unlocked();
+//!
+//! let (_first, raised) = first.locker().users().lock(&mut token).unwrap();
+//! let (_second, _) = second.locker().users().lock(&mut token).unwrap();
+//! drop(raised);
+//! ```
+
+use std::{
+    marker::PhantomData,
+    sync::{Mutex, PoisonError},
+    thread::{self, ThreadId},
+};
+
+/// A lock level, such as [L0]
+pub trait Level {}
+
+/// Implemented by levels which may be locked while holding locks of level `L`
+pub trait LockAfter<L: Level>: Level {}
+
+/// The level below all others, held while no locks are held
+pub enum Unlocked {}
+
+impl Level for Unlocked {}
+
+macro_rules! levels {
+    ($level:ident $(, $higher:ident)*) => {
+        #[doc = concat!("The lock level set through `#[locker(level = ", stringify!($level), ")]`")]
+        pub enum $level {}
+
+        impl Level for $level {}
+        impl LockAfter<Unlocked> for $level {}
+        $(impl LockAfter<$level> for $higher {})*
+
+        levels!($($higher),*);
+    };
+    () => {};
+}
+
+levels!(
+    L0, L1, L2, L3, L4, L5, L6, L7, L8, L9, L10, L11, L12, L13, L14, L15, L16, L17, L18, L19, L20,
+    L21, L22, L23, L24, L25, L26, L27, L28, L29, L30, L31
+);
+
+/// Proof that only locks of levels above `L` are held
+///
+/// Tokens obtained from another token, either by locking a struct with a level or through
+/// [LockToken::raise], keep that token borrowed for their lifetime `'t`. Functions may require a
+/// token to document and enforce which locks may be held when calling them, such as
+/// `fn update(token: &mut LockToken<Unlocked>)` for functions which may not be called while holding
+/// any locks.
+///
+/// Tokens are neither [Send] nor [Sync], as they vouch for the locks held by the current thread.
+pub struct LockToken<'t, L: Level> {
+    root: Option<ThreadId>,
+    _level: PhantomData<(&'t mut (), L, *const ())>,
+}
+
+/// The threads currently holding a token created through [LockToken::unlocked]
+static ROOTS: Mutex<Vec<ThreadId>> = Mutex::new(Vec::new());
+
+impl LockToken<'static, Unlocked> {
+    /// Creates the token of the current thread asserting that no locks are held
+    ///
+    /// # Panics
+    ///
+    /// Panics if a token created through this function on the current thread is still alive
+    pub fn unlocked() -> Self {
+        let thread = thread::current().id();
+        let mut roots = ROOTS.lock().unwrap_or_else(PoisonError::into_inner);
+        if roots.contains(&thread) {
+            panic!("A lock token already exists on this thread");
+        }
+        roots.push(thread);
+
+        LockToken {
+            root: Some(thread),
+            _level: PhantomData,
+        }
+    }
+
+    /// Creates a token asserting that no locks are held, without checking for other tokens
+    ///
+    /// # Safety
+    ///
+    /// No locks with a level may be held by the current thread, and the token may not be used
+    /// while locks acquired with another token are held
+    pub unsafe fn new() -> Self {
+        LockToken {
+            root: None,
+            _level: PhantomData,
+        }
+    }
+}
+
+impl<'t, L: Level> LockToken<'t, L> {
+    /// Borrows the token as a token of a higher level, such as to call a function requiring one
+    pub fn raise<M>(&mut self) -> LockToken<'_, M>
+    where
+        M: LockAfter<L>,
+    {
+        LockToken {
+            root: None,
+            _level: PhantomData,
+        }
+    }
+}
+
+impl<'t, L: Level> Drop for LockToken<'t, L> {
+    fn drop(&mut self) {
+        if let Some(thread) = self.root {
+            ROOTS
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .retain(|root| *root != thread);
+        }
+    }
+}
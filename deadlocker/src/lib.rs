@@ -4,9 +4,51 @@ extern crate deadlocker_derive;
 #[cfg(feature = "derive")]
 pub use deadlocker_derive::Locker;
 
+pub mod condvar;
+pub mod debug;
+pub mod elements;
+mod error;
+#[cfg(feature = "lock-graph")]
+pub mod graph;
+pub mod guard;
+#[cfg(any(feature = "debug-order-check", feature = "lock-graph"))]
+pub mod held;
+pub mod hold;
+mod info;
+mod instance;
+pub mod level;
+pub mod observer;
+#[cfg(feature = "debug-order-check")]
+mod order;
+#[cfg(feature = "serde")]
+pub mod serde_support;
+pub mod stats;
+#[cfg(feature = "tracing")]
+pub mod trace;
+pub mod transaction;
+pub mod watch;
+#[cfg(feature = "watchdog")]
+pub mod watchdog;
+
+pub use condvar::Condvar;
+pub use elements::Elements;
+pub use error::LockError;
+pub use info::{LockFieldInfo, LockKind, LockSetInfo, LockerMeta};
+pub use instance::{lock_all, lock_pair, LockInstance};
+pub use level::LockToken;
+pub use observer::LockObserver;
+pub use transaction::Transaction;
+
 pub trait Locker<'a> {
     type LockBuilder
     where
         Self: 'a;
     fn locker(&'a mut self) -> Self::LockBuilder;
 }
+
+/// The final `lock` method of a synchronous builder chain, allowing generic code to acquire the
+/// selected locks
+pub trait Lock {
+    type Output;
+    fn lock(self) -> Self::Output;
+}
@@ -0,0 +1,279 @@
+//! Observation of the acquisitions made by generated `lock` methods
+//!
+//! Observers are registered per struct through `#[locker(observer = "path::to::OBSERVER")]`, or,
+//! with the `observer` feature enabled, globally for all structs through [set_observer].
+
+use std::{
+    error::Error,
+    panic::Location,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+    time::Instant,
+};
+
+use crate::{LockFieldInfo, LockSetInfo};
+
+/// The acquisition of a single field, as passed to a [LockObserver]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LockContext {
+    pub set: &'static LockSetInfo,
+    pub field: &'static LockFieldInfo,
+    /// The location of the `lock` call acquiring the set
+    pub location: &'static Location<'static>,
+}
+
+/// Receives events about the acquisition of the fields of generated lockers
+///
+/// All methods do nothing by default
+pub trait LockObserver: Send + Sync {
+    /// Called right before attempting to acquire a field
+    fn before_acquire(&self, _context: &LockContext) {}
+
+    /// Called once a field has been acquired, after waiting on it for `wait`
+    fn after_acquire(&self, _context: &LockContext, _wait: Duration) {}
+
+    /// Called when a field could not be acquired without waiting, right before waiting on it
+    fn on_contended(&self, _context: &LockContext) {}
+
+    /// Called when the guard of a field is dropped, after having been held for `hold`, along with
+    /// whether the guard was mutably dereferenced
+    fn on_release(&self, _context: &LockContext, _hold: Duration, _dirty: bool) {}
+
+    /// Called when a field could not be acquired, such as when it is poisoned or the selected
+    /// element does not exist
+    fn on_error(&self, _context: &LockContext, _error: &dyn Error) {}
+}
+
+#[cfg(feature = "observer")]
+static OBSERVER: std::sync::RwLock<Option<Box<dyn LockObserver>>> = std::sync::RwLock::new(None);
+
+/// Installs an observer notified about the acquisitions of all structs, replacing any previously
+/// installed observer
+#[cfg(feature = "observer")]
+pub fn set_observer(observer: impl LockObserver + 'static) {
+    *OBSERVER
+        .write()
+        .unwrap_or_else(std::sync::PoisonError::into_inner) = Some(Box::new(observer));
+}
+
+/// The observers of a single generated `lock` call
+#[derive(Clone, Copy)]
+pub struct Observers {
+    set: &'static LockSetInfo,
+    location: &'static Location<'static>,
+    local: Option<&'static dyn LockObserver>,
+}
+
+impl Observers {
+    pub fn new(
+        set: &'static LockSetInfo,
+        location: &'static Location<'static>,
+        local: Option<&'static dyn LockObserver>,
+    ) -> Self {
+        Observers {
+            set,
+            location,
+            local,
+        }
+    }
+
+    fn notify(&self, field: &'static LockFieldInfo, f: impl Fn(&dyn LockObserver, &LockContext)) {
+        let context = LockContext {
+            set: self.set,
+            field,
+            location: self.location,
+        };
+
+        if let Some(local) = self.local {
+            f(local, &context);
+        }
+
+        #[cfg(feature = "observer")]
+        if let Some(global) = &*OBSERVER
+            .read()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+        {
+            f(global.as_ref(), &context);
+        }
+    }
+
+    /// Notifies the observers that `field` is about to be acquired
+    pub fn before_acquire(&self, field: &'static LockFieldInfo) -> ObserverToken {
+        self.notify(field, |observer, context| observer.before_acquire(context));
+        ObserverToken {
+            observers: *self,
+            field,
+            started: Instant::now(),
+            acquired: false,
+            dirty: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Notifies the observers that `field` could not be acquired
+    pub fn on_error(&self, field: &'static LockFieldInfo, error: &dyn Error) {
+        self.notify(field, |observer, context| observer.on_error(context, error));
+    }
+}
+
+/// The acquisition of a single field, notifying the observers of its release when dropped
+pub struct ObserverToken {
+    observers: Observers,
+    field: &'static LockFieldInfo,
+    started: Instant,
+    acquired: bool,
+    dirty: Arc<AtomicBool>,
+}
+
+impl ObserverToken {
+    /// Notifies the observers that the field is contended
+    pub fn contended(&self) {
+        self.observers
+            .notify(self.field, |observer, context| observer.on_contended(context));
+    }
+
+    /// Returns the flag raised by the guard of the field when mutably dereferenced, see
+    /// [crate::guard::Dirty::shared]
+    pub fn dirty_flag(&self) -> Arc<AtomicBool> {
+        self.dirty.clone()
+    }
+
+    /// Notifies the observers that the field has been acquired
+    pub fn acquired(&mut self) {
+        let now = Instant::now();
+        let wait = now - self.started;
+        self.started = now;
+        self.acquired = true;
+        self.observers
+            .notify(self.field, |observer, context| observer.after_acquire(context, wait));
+    }
+}
+
+impl Drop for ObserverToken {
+    fn drop(&mut self) {
+        if self.acquired {
+            let hold = self.started.elapsed();
+            let dirty = self.dirty.load(Ordering::Relaxed);
+            self.observers.notify(self.field, |observer, context| {
+                observer.on_release(context, hold, dirty)
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::LockKind;
+
+    /// Records the events it receives, as `<hook> <field>`
+    struct Recorder(Mutex<Vec<String>>);
+
+    impl Recorder {
+        const fn new() -> Self {
+            Recorder(Mutex::new(Vec::new()))
+        }
+
+        fn record(&self, event: &str, context: &LockContext) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("{} {}", event, context.field.field_name));
+        }
+
+        fn events(&self) -> Vec<String> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    impl LockObserver for Recorder {
+        fn before_acquire(&self, context: &LockContext) {
+            self.record("before_acquire", context);
+        }
+
+        fn after_acquire(&self, context: &LockContext, _wait: Duration) {
+            self.record("after_acquire", context);
+        }
+
+        fn on_contended(&self, context: &LockContext) {
+            self.record("on_contended", context);
+        }
+
+        fn on_release(&self, context: &LockContext, _hold: Duration, dirty: bool) {
+            self.record(if dirty { "on_release dirty" } else { "on_release" }, context);
+        }
+
+        fn on_error(&self, context: &LockContext, error: &dyn Error) {
+            self.record(&format!("on_error ({})", error), context);
+        }
+    }
+
+    static SET: LockSetInfo = LockSetInfo {
+        module_path: module_path!(),
+        struct_name: "Observed",
+        field_names: &["config", "cache"],
+    };
+
+    static CONFIG: LockFieldInfo = field("config", 0);
+    static CACHE: LockFieldInfo = field("cache", 1);
+
+    const fn field(field_name: &'static str, rank: usize) -> LockFieldInfo {
+        LockFieldInfo {
+            module_path: module_path!(),
+            struct_name: "Observed",
+            field_name,
+            rank,
+            inner_type: "u32",
+            is_async: false,
+            is_result: true,
+            kind: LockKind::Single,
+        }
+    }
+
+    #[test]
+    fn hooks_are_called_in_acquisition_order() {
+        static RECORDER: Recorder = Recorder::new();
+        let observers = Observers::new(&SET, Location::caller(), Some(&RECORDER));
+
+        let mut config = observers.before_acquire(&CONFIG);
+        config.acquired();
+        let mut cache = observers.before_acquire(&CACHE);
+        cache.contended();
+        cache.acquired();
+        cache.dirty_flag().store(true, Ordering::Relaxed);
+        drop(config);
+        drop(cache);
+
+        assert_eq!(
+            RECORDER.events(),
+            [
+                "before_acquire config",
+                "after_acquire config",
+                "before_acquire cache",
+                "on_contended cache",
+                "after_acquire cache",
+                "on_release config",
+                "on_release dirty cache",
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_acquisition_is_not_released() {
+        static RECORDER: Recorder = Recorder::new();
+        let observers = Observers::new(&SET, Location::caller(), Some(&RECORDER));
+
+        let config = observers.before_acquire(&CONFIG);
+        observers.on_error(&CONFIG, &std::io::Error::other("poisoned"));
+        drop(config);
+
+        assert_eq!(
+            RECORDER.events(),
+            ["before_acquire config", "on_error (poisoned) config"]
+        );
+    }
+}
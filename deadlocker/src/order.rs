@@ -0,0 +1,169 @@
+//! Debug-build validation of the order in which locks are acquired across lockers
+//!
+//! Whenever a lock is acquired while another is held, the pair is ranked globally, such that the
+//! first observed nesting decides the order. Acquiring a pair in the opposite order later, on any
+//! thread, is an inversion which could deadlock, and panics with the sites of both acquisitions.
+//! Acquiring a field while holding the same field of another instance is reported right away, as
+//! the instances could be locked in the opposite order elsewhere, unless they are locked through
+//! [lock_pair](crate::lock_pair) or [lock_all](crate::lock_all). In release builds no validation
+//! takes place.
+
+use std::{
+    collections::HashMap,
+    sync::{Mutex, PoisonError},
+};
+
+use crate::held::{Acquiring, Class, Held, Site};
+
+/// The first observed acquisition of one lock while another was held
+struct Nesting {
+    outer_site: Site,
+    inner_site: Site,
+}
+
+static RANKS: Mutex<Option<HashMap<(Class, Class), Nesting>>> = Mutex::new(None);
+
+/// Ranks the acquisition of a lock after each of the `held` locks, returning a description of the
+/// first inversion of a previously ranked pair, or of a recursive acquisition of the same field
+pub(crate) fn check(held: &[Held], acquiring: &Acquiring) -> Option<String> {
+    if !cfg!(debug_assertions) {
+        return None;
+    }
+
+    let (class, site) = (&acquiring.class, acquiring.site);
+    if let Some(outer) = held.iter().find(|h| acquiring.recurses(h)) {
+        return Some(format!(
+            "Recursive locking: acquiring {} at {} while holding {} of another instance acquired \
+             at {}, lock the instances through lock_pair or lock_all instead",
+            class.0, site, outer.class.0, outer.site,
+        ));
+    }
+
+    let mut ranks = RANKS.lock().unwrap_or_else(PoisonError::into_inner);
+    let ranks = ranks.get_or_insert_with(HashMap::new);
+
+    for outer in held.iter().filter(|h| h.class != *class) {
+        if let Some(nesting) = ranks.get(&(*class, outer.class)) {
+            return Some(format!(
+                "Lock order inversion: acquiring {} at {} while holding {} acquired at {}, \
+                 but {} was previously held (acquired at {}) while acquiring {} at {}",
+                class.0,
+                site,
+                outer.class.0,
+                outer.site,
+                class.0,
+                nesting.outer_site,
+                outer.class.0,
+                nesting.inner_site,
+            ));
+        }
+
+        ranks
+            .entry((outer.class, *class))
+            .or_insert_with(|| Nesting {
+                outer_site: outer.site,
+                inner_site: site,
+            });
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic::Location;
+
+    use crate::{
+        held::{acquire, ChainScope, InstanceScope},
+        LockFieldInfo, LockKind,
+    };
+
+    /// Returns the description of a field, with a struct name unique to each test as the ranks
+    /// are shared by all tests
+    fn info(struct_name: &'static str, field_name: &'static str) -> LockFieldInfo {
+        LockFieldInfo {
+            module_path: module_path!(),
+            struct_name,
+            field_name,
+            rank: 0,
+            inner_type: "u32",
+            is_async: false,
+            is_result: true,
+            kind: LockKind::Single,
+        }
+    }
+
+    #[test]
+    fn consistent_order_passes() {
+        let (a, b) = (info("Consistent", "a"), info("Consistent", "b"));
+        for _ in 0..2 {
+            let _a = acquire(&a, Location::caller());
+            let _b = acquire(&b, Location::caller());
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Lock order inversion")]
+    fn inversion_panics() {
+        let (a, b) = (info("Inversion", "a"), info("Inversion", "b"));
+        {
+            let _a = acquire(&a, Location::caller());
+            let _b = acquire(&b, Location::caller());
+        }
+        let _b = acquire(&b, Location::caller());
+        let _a = acquire(&a, Location::caller());
+    }
+
+    #[test]
+    fn inversion_across_threads_panics() {
+        let (a, b) = (info("Threads", "a"), info("Threads", "b"));
+        std::thread::spawn(move || {
+            let _a = acquire(&a, Location::caller());
+            let _b = acquire(&b, Location::caller());
+        })
+        .join()
+        .unwrap();
+
+        let inverted = std::thread::spawn(move || {
+            let _b = acquire(&b, Location::caller());
+            let _a = acquire(&a, Location::caller());
+        })
+        .join();
+        assert!(inverted.is_err());
+    }
+
+    #[test]
+    fn released_locks_are_not_ranked() {
+        let (a, b) = (info("Released", "a"), info("Released", "b"));
+        drop(acquire(&a, Location::caller()));
+        let _b = acquire(&b, Location::caller());
+        let _a = acquire(&a, Location::caller());
+    }
+
+    #[test]
+    #[should_panic(expected = "Recursive locking")]
+    fn same_field_of_two_instances_panics() {
+        let a = info("Recursive", "a");
+        let _first = acquire(&a, Location::caller());
+        let _second = acquire(&a, Location::caller());
+    }
+
+    #[test]
+    fn same_field_within_one_chain_passes() {
+        let a = info("Chain", "a");
+        let _chain = ChainScope::enter();
+        let _first = acquire(&a, Location::caller());
+        let _second = acquire(&a, Location::caller());
+    }
+
+    #[test]
+    fn same_field_of_scoped_instances_passes() {
+        let a = info("Scoped", "a");
+        let _first = {
+            let _scope = InstanceScope::enter(0);
+            acquire(&a, Location::caller())
+        };
+        let _scope = InstanceScope::enter(1);
+        let _second = acquire(&a, Location::caller());
+    }
+}
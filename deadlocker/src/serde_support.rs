@@ -0,0 +1,37 @@
+//! Support for the `Serialize` and `Deserialize` implementations generated with the `serde`
+//! feature
+//!
+//! Serializing a struct acquires all of its locks in lock order, holding them until all values
+//! have been serialized. Deserializing constructs the locks through the implementations provided
+//! by serde, such as for `Arc` and `Mutex`.
+
+use std::ops::Deref;
+
+pub use serde;
+use serde::{ser::SerializeMap, ser::SerializeSeq, Serialize, Serializer};
+
+/// The guards of the elements of a `collection` field, serialized as a sequence of their values
+pub struct ElementGuards<'s, 'g, T: ?Sized>(pub &'s [Box<dyn Deref<Target = T> + 'g>]);
+
+impl<'s, 'g, T: Serialize + ?Sized> Serialize for ElementGuards<'s, 'g, T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(self.0.len()))?;
+        for guard in self.0 {
+            seq.serialize_element(&***guard)?;
+        }
+        seq.end()
+    }
+}
+
+/// The guards of the entries of a `keyed` field, serialized as a map of their values
+pub struct EntryGuards<'s, 'g, K, T: ?Sized>(pub &'s [(&'g K, Box<dyn Deref<Target = T> + 'g>)]);
+
+impl<'s, 'g, K: Serialize, T: Serialize + ?Sized> Serialize for EntryGuards<'s, 'g, K, T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (key, guard) in self.0 {
+            map.serialize_entry(key, &***guard)?;
+        }
+        map.end()
+    }
+}
@@ -0,0 +1,253 @@
+//! Per field lock statistics, collected for structs marked with `#[locker(stats)]`
+//!
+//! The statistics of a struct are available through its generated `lock_stats` function. With the
+//! `metrics` feature enabled, they are additionally reported through the [metrics] facade as the
+//! counters `deadlocker_acquisitions_total` and `deadlocker_contended_total`, and the histograms
+//! `deadlocker_wait_seconds` and `deadlocker_hold_seconds`, labelled by `struct` and `field`.
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use crate::{debug::Attempt, LockFieldInfo};
+
+/// The statistics of the acquisitions of a single field
+pub struct FieldStats {
+    field: &'static LockFieldInfo,
+    acquisitions: AtomicU64,
+    contended: AtomicU64,
+    wait_nanos: AtomicU64,
+    max_hold_nanos: AtomicU64,
+}
+
+impl FieldStats {
+    pub const fn new(field: &'static LockFieldInfo) -> Self {
+        FieldStats {
+            field,
+            acquisitions: AtomicU64::new(0),
+            contended: AtomicU64::new(0),
+            wait_nanos: AtomicU64::new(0),
+            max_hold_nanos: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the field the statistics describe
+    pub fn field(&self) -> &'static LockFieldInfo {
+        self.field
+    }
+
+    /// Returns the number of times the field has been locked
+    pub fn acquisitions(&self) -> u64 {
+        self.acquisitions.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of times the field could not be locked without waiting
+    pub fn contended(&self) -> u64 {
+        self.contended.load(Ordering::Relaxed)
+    }
+
+    /// Returns the total time spent waiting on the field
+    pub fn total_wait(&self) -> Duration {
+        Duration::from_nanos(self.wait_nanos.load(Ordering::Relaxed))
+    }
+
+    /// Returns the longest time the field has been held
+    pub fn max_hold(&self) -> Duration {
+        Duration::from_nanos(self.max_hold_nanos.load(Ordering::Relaxed))
+    }
+
+    /// Registers that the field is being locked, to be called right before acquiring it
+    pub fn begin(&'static self) -> StatsToken {
+        StatsToken {
+            stats: self,
+            started: Instant::now(),
+        }
+    }
+}
+
+/// The acquisition of a single field, recording the hold time when dropped
+pub struct StatsToken {
+    stats: &'static FieldStats,
+    started: Instant,
+}
+
+impl StatsToken {
+    /// Registers that the lock has been acquired, after waiting for it if `contended`
+    pub fn acquired(&mut self, contended: bool) {
+        let now = Instant::now();
+        let wait = now - self.started;
+        self.started = now;
+
+        let stats = self.stats;
+        stats.acquisitions.fetch_add(1, Ordering::Relaxed);
+        stats.wait_nanos.fetch_add(nanos(wait), Ordering::Relaxed);
+        if contended {
+            stats.contended.fetch_add(1, Ordering::Relaxed);
+        }
+
+        #[cfg(feature = "metrics")]
+        {
+            let labels = [
+                ("struct", stats.field.struct_name),
+                ("field", stats.field.field_name),
+            ];
+            metrics::counter!("deadlocker_acquisitions_total", &labels).increment(1);
+            if contended {
+                metrics::counter!("deadlocker_contended_total", &labels).increment(1);
+            }
+            metrics::histogram!("deadlocker_wait_seconds", &labels).record(wait);
+        }
+    }
+}
+
+impl Drop for StatsToken {
+    fn drop(&mut self) {
+        let hold = self.started.elapsed();
+        self.stats
+            .max_hold_nanos
+            .fetch_max(nanos(hold), Ordering::Relaxed);
+
+        #[cfg(feature = "metrics")]
+        {
+            let labels = [
+                ("struct", self.stats.field.struct_name),
+                ("field", self.stats.field.field_name),
+            ];
+            metrics::histogram!("deadlocker_hold_seconds", &labels).record(hold);
+        }
+    }
+}
+
+fn nanos(duration: Duration) -> u64 {
+    u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX)
+}
+
+/// The result of a non-blocking lock attempt, such as the [Option] returned by `parking_lot` or
+/// the [Result] returned by the standard library and `tokio`
+///
+/// Used both to detect contention and by the `Debug` implementation of `#[locker(debug)]`
+pub trait TryAcquire: Sized {
+    type Guard;
+
+    /// Returns the guard, or whether the lock is held elsewhere or poisoned
+    fn attempt(self) -> Attempt<Self::Guard>;
+
+    /// Returns the guard if the lock was acquired
+    fn try_acquire(self) -> Option<Self::Guard> {
+        match self.attempt() {
+            Attempt::Acquired(guard) => Some(guard),
+            Attempt::Locked | Attempt::Poisoned => None,
+        }
+    }
+}
+
+impl<G> TryAcquire for Option<G> {
+    type Guard = G;
+    fn attempt(self) -> Attempt<G> {
+        match self {
+            Some(guard) => Attempt::Acquired(guard),
+            None => Attempt::Locked,
+        }
+    }
+}
+
+impl<G, E: TryLockFailure> TryAcquire for Result<G, E> {
+    type Guard = G;
+    fn attempt(self) -> Attempt<G> {
+        match self {
+            Ok(guard) => Attempt::Acquired(guard),
+            Err(error) if error.is_poisoned() => Attempt::Poisoned,
+            Err(_) => Attempt::Locked,
+        }
+    }
+}
+
+/// The error of a failed non-blocking lock attempt
+///
+/// Implement this for the error returned by the `try_lock_method` of custom lock types
+pub trait TryLockFailure {
+    /// Returns whether the attempt failed because the lock is poisoned rather than held
+    fn is_poisoned(&self) -> bool;
+}
+
+impl<T> TryLockFailure for std::sync::TryLockError<T> {
+    fn is_poisoned(&self) -> bool {
+        matches!(self, std::sync::TryLockError::Poisoned(_))
+    }
+}
+
+/// The result of a non-blocking attempt on an asynchronous lock, which can not be poisoned
+///
+/// Failed attempts always count as held elsewhere, so the error of a [Result] needs no
+/// [TryLockFailure] implementation.
+pub struct Unpoisoned<R>(pub R);
+
+impl<G> TryAcquire for Unpoisoned<Option<G>> {
+    type Guard = G;
+    fn attempt(self) -> Attempt<G> {
+        self.0.attempt()
+    }
+}
+
+impl<G, E> TryAcquire for Unpoisoned<Result<G, E>> {
+    type Guard = G;
+    fn attempt(self) -> Attempt<G> {
+        match self.0 {
+            Ok(guard) => Attempt::Acquired(guard),
+            Err(_) => Attempt::Locked,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+
+    use super::*;
+    use crate::LockKind;
+
+    static CONFIG: LockFieldInfo = LockFieldInfo {
+        module_path: module_path!(),
+        struct_name: "Counted",
+        field_name: "config",
+        rank: 0,
+        inner_type: "u32",
+        is_async: false,
+        is_result: true,
+        kind: LockKind::Single,
+    };
+
+    #[test]
+    fn acquisitions_are_counted() {
+        static STATS: FieldStats = FieldStats::new(&CONFIG);
+
+        let mut first = STATS.begin();
+        sleep(Duration::from_millis(5));
+        first.acquired(false);
+        drop(first);
+        let mut second = STATS.begin();
+        second.acquired(true);
+        drop(second);
+
+        assert_eq!(STATS.field(), &CONFIG);
+        assert_eq!(STATS.acquisitions(), 2);
+        assert_eq!(STATS.contended(), 1);
+        assert!(STATS.total_wait() >= Duration::from_millis(5));
+    }
+
+    #[test]
+    fn longest_hold_is_kept() {
+        static STATS: FieldStats = FieldStats::new(&CONFIG);
+
+        let mut long = STATS.begin();
+        long.acquired(false);
+        sleep(Duration::from_millis(20));
+        drop(long);
+        let mut short = STATS.begin();
+        short.acquired(false);
+        drop(short);
+
+        assert!(STATS.max_hold() >= Duration::from_millis(20));
+    }
+}
@@ -0,0 +1,94 @@
+//! Integration with [tracing], describing the acquisition of guard sets with spans and events
+//!
+//! Every generated `lock` method opens a `lock` span for the duration of the acquisition, emits an
+//! event for each acquired lock with the time spent waiting on it, and a final event with the total
+//! hold time once all guards of the set have been released.
+
+use std::{
+    future::Future,
+    panic::Location,
+    sync::{Arc, Mutex, PoisonError},
+    time::Instant,
+};
+
+use tracing::{debug, debug_span, instrument::Instrumented, span::Entered, Instrument, Span};
+
+use crate::LockFieldInfo;
+
+/// The span of a guard set, which reports the total hold time once all of its guards are released
+pub struct LockSet {
+    span: Span,
+    started: Instant,
+    acquired: Mutex<Option<Instant>>,
+}
+
+impl LockSet {
+    /// Enters the span of the guard set for the duration of a synchronous acquisition
+    pub fn enter(&self) -> Entered<'_> {
+        self.span.enter()
+    }
+
+    /// Instruments an asynchronous acquisition with the span of the guard set
+    pub fn instrument<F: Future>(&self, future: F) -> Instrumented<F> {
+        future.instrument(self.span.clone())
+    }
+
+    /// Registers that all locks of the guard set have been acquired
+    pub fn acquired(&self) {
+        let now = Instant::now();
+        *self.acquired.lock().unwrap_or_else(PoisonError::into_inner) = Some(now);
+        debug!(parent: &self.span, wait = ?(now - self.started), "acquired guard set");
+    }
+}
+
+impl Drop for LockSet {
+    fn drop(&mut self) {
+        let acquired = *self.acquired.get_mut().unwrap_or_else(PoisonError::into_inner);
+        if let Some(acquired) = acquired {
+            debug!(parent: &self.span, hold = ?acquired.elapsed(), "released guard set");
+        }
+    }
+}
+
+/// Opens the span of a guard set of `struct_name`, consisting of `fields`, being locked at
+/// `location`
+pub fn begin(
+    struct_name: &'static str,
+    fields: &'static str,
+    location: &'static Location<'static>,
+) -> Arc<LockSet> {
+    let span = debug_span!("lock", r#struct = struct_name, fields, location = %location);
+    Arc::new(LockSet {
+        span,
+        started: Instant::now(),
+        acquired: Mutex::new(None),
+    })
+}
+
+/// Registers that a lock of `field` is being waited on, to be called right before acquiring it
+pub fn wait(set: &Arc<LockSet>, field: &'static LockFieldInfo) -> FieldToken {
+    FieldToken {
+        set: Arc::clone(set),
+        field,
+        started: Instant::now(),
+    }
+}
+
+/// The acquisition of a single lock in a guard set, keeping the guard set alive until dropped
+pub struct FieldToken {
+    set: Arc<LockSet>,
+    field: &'static LockFieldInfo,
+    started: Instant,
+}
+
+impl FieldToken {
+    /// Registers that the lock has been acquired
+    pub fn acquired(&self) {
+        debug!(
+            parent: &self.set.span,
+            field = self.field.field_name,
+            wait = ?self.started.elapsed(),
+            "acquired lock"
+        );
+    }
+}
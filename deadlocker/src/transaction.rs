@@ -0,0 +1,163 @@
+//! Transactions over the locked fields, acquired through the `lock_transaction` method of the
+//! generated builders of structs marked with `#[locker(transaction)]`
+//!
+//! The values of the fields are cloned once acquired, and restored unless the transaction is
+//! committed, including when unwinding from a panic. Only the fields which were mutably accessed
+//! are restored, see `dirty_fields`.
+
+use std::ops::{Deref, DerefMut};
+
+/// Implemented by the generated states whose locked values all implement [Clone]
+pub trait Transactional {
+    /// The clones of the locked values
+    type Snapshot;
+
+    /// Clones the locked values
+    fn snapshot(&self) -> Self::Snapshot;
+
+    /// Restores the locked values which were mutably accessed from a snapshot
+    fn restore(&mut self, snapshot: Self::Snapshot);
+}
+
+/// A generated state whose changes are rolled back unless committed
+///
+/// Dereferences to the state, so the guards are accessed as with the state itself.
+pub struct Transaction<S: Transactional> {
+    state: Option<S>,
+    snapshot: Option<S::Snapshot>,
+}
+
+impl<S: Transactional> Transaction<S> {
+    /// Starts a transaction over the values locked by `state`
+    pub fn new(state: S) -> Self {
+        let snapshot = state.snapshot();
+        Transaction {
+            state: Some(state),
+            snapshot: Some(snapshot),
+        }
+    }
+
+    /// Keeps the changes, returning the state with the locks still held
+    pub fn commit(mut self) -> S {
+        self.snapshot = None;
+        self.state.take().expect("Transaction was already finished")
+    }
+
+    /// Restores the values as they were when acquired, then releases the locks
+    pub fn rollback(self) {
+        drop(self);
+    }
+
+    /// Runs `f` on the state, committing if it succeeds and rolling back if it fails or panics
+    pub fn run<R, E>(mut self, f: impl FnOnce(&mut S) -> Result<R, E>) -> Result<R, E> {
+        let result = f(&mut self);
+        if result.is_ok() {
+            self.commit();
+        }
+        result
+    }
+}
+
+impl<S: Transactional> Deref for Transaction<S> {
+    type Target = S;
+
+    fn deref(&self) -> &Self::Target {
+        self.state.as_ref().expect("Transaction was already finished")
+    }
+}
+
+impl<S: Transactional> DerefMut for Transaction<S> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.state.as_mut().expect("Transaction was already finished")
+    }
+}
+
+impl<S: Transactional> Drop for Transaction<S> {
+    fn drop(&mut self) {
+        if let (Some(state), Some(snapshot)) = (&mut self.state, self.snapshot.take()) {
+            state.restore(snapshot);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic::{self, AssertUnwindSafe};
+
+    struct Balance<'a>(&'a mut i64);
+
+    impl Transactional for Balance<'_> {
+        type Snapshot = i64;
+
+        fn snapshot(&self) -> i64 {
+            *self.0
+        }
+
+        fn restore(&mut self, snapshot: i64) {
+            *self.0 = snapshot;
+        }
+    }
+
+    #[test]
+    fn commit_keeps_changes() {
+        let mut balance = 10;
+        let mut transaction = Transaction::new(Balance(&mut balance));
+        *transaction.0 -= 3;
+        transaction.commit();
+
+        assert_eq!(balance, 7);
+    }
+
+    #[test]
+    fn rollback_restores_values() {
+        let mut balance = 10;
+        let mut transaction = Transaction::new(Balance(&mut balance));
+        *transaction.0 -= 3;
+        transaction.rollback();
+
+        assert_eq!(balance, 10);
+    }
+
+    #[test]
+    fn drop_restores_values() {
+        let mut balance = 10;
+        {
+            let mut transaction = Transaction::new(Balance(&mut balance));
+            *transaction.0 -= 3;
+        }
+
+        assert_eq!(balance, 10);
+    }
+
+    #[test]
+    fn panic_restores_values() {
+        let mut balance = 10;
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut transaction = Transaction::new(Balance(&mut balance));
+            *transaction.0 -= 3;
+            panic!("Insufficient funds");
+        }));
+
+        assert!(result.is_err());
+        assert_eq!(balance, 10);
+    }
+
+    #[test]
+    fn run_commits_on_success_and_restores_on_error() {
+        let mut balance = 10;
+        let result: Result<(), &str> = Transaction::new(Balance(&mut balance)).run(|state| {
+            *state.0 -= 3;
+            Ok(())
+        });
+        assert!(result.is_ok());
+        assert_eq!(balance, 7);
+
+        let result: Result<(), &str> = Transaction::new(Balance(&mut balance)).run(|state| {
+            *state.0 -= 10;
+            Err("Insufficient funds")
+        });
+        assert_eq!(result, Err("Insufficient funds"));
+        assert_eq!(balance, 7);
+    }
+}
@@ -0,0 +1,285 @@
+//! Notifications of mutations of fields marked with `#[locker(watch)]`
+//!
+//! Subscriptions are tied to the lock shared through the field, so that every instance sharing
+//! the lock notifies the same subscribers. Each notification carries the number of mutations since
+//! the first live subscription to the lock was made.
+//!
+//! A subscription keeps the lock alive, so no other lock can take its place while it is in use,
+//! and is removed as soon as it is dropped.
+
+use std::{
+    ops::{Deref, DerefMut},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Mutex, PoisonError,
+    },
+};
+
+struct Subscribers {
+    key: usize,
+    version: u64,
+    senders: Vec<(u64, mpsc::Sender<u64>)>,
+    #[cfg(feature = "tokio")]
+    watch: Option<tokio::sync::watch::Sender<u64>>,
+    #[cfg(feature = "tokio")]
+    watchers: usize,
+}
+
+impl Subscribers {
+    fn is_empty(&self) -> bool {
+        #[cfg(feature = "tokio")]
+        if self.watchers > 0 {
+            return false;
+        }
+        self.senders.is_empty()
+    }
+}
+
+static SUBSCRIBERS: Mutex<Vec<Subscribers>> = Mutex::new(Vec::new());
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the key identifying a shared lock
+pub fn key<T: ?Sized>(lock: &T) -> usize {
+    lock as *const T as *const () as usize
+}
+
+fn with_subscribers<R>(key: usize, f: impl FnOnce(&mut Subscribers) -> R) -> R {
+    let mut subscribers = SUBSCRIBERS.lock().unwrap_or_else(PoisonError::into_inner);
+    let position = match subscribers.iter().position(|entry| entry.key == key) {
+        Some(position) => position,
+        None => {
+            subscribers.push(Subscribers {
+                key,
+                version: 0,
+                senders: Vec::new(),
+                #[cfg(feature = "tokio")]
+                watch: None,
+                #[cfg(feature = "tokio")]
+                watchers: 0,
+            });
+            subscribers.len() - 1
+        }
+    };
+    let result = f(&mut subscribers[position]);
+    if subscribers[position].is_empty() {
+        subscribers.swap_remove(position);
+    }
+    result
+}
+
+/// Keeps the subscribed lock alive, removing the subscription when dropped
+struct Subscription<P> {
+    key: usize,
+    /// The id of the sender, or `None` for a subscription through a [tokio::sync::watch] channel
+    id: Option<u64>,
+    _lock: P,
+}
+
+impl<P> Drop for Subscription<P> {
+    fn drop(&mut self) {
+        with_subscribers(self.key, |subscribers| match self.id {
+            Some(id) => subscribers.senders.retain(|(sender, _)| *sender != id),
+            #[cfg(feature = "tokio")]
+            None => {
+                subscribers.watchers -= 1;
+                if subscribers.watchers == 0 {
+                    subscribers.watch = None;
+                }
+            }
+            #[cfg(not(feature = "tokio"))]
+            None => {}
+        });
+    }
+}
+
+/// The receiving end of a subscription, receiving the new version after each mutation
+pub struct Receiver<P> {
+    receiver: mpsc::Receiver<u64>,
+    _subscription: Subscription<P>,
+}
+
+impl<P> Deref for Receiver<P> {
+    type Target = mpsc::Receiver<u64>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.receiver
+    }
+}
+
+/// The receiving end of a subscription made with the `tokio` feature, holding the latest version
+#[cfg(feature = "tokio")]
+pub struct AsyncReceiver<P> {
+    receiver: tokio::sync::watch::Receiver<u64>,
+    _subscription: Subscription<P>,
+}
+
+#[cfg(feature = "tokio")]
+impl<P> Deref for AsyncReceiver<P> {
+    type Target = tokio::sync::watch::Receiver<u64>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.receiver
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<P> DerefMut for AsyncReceiver<P> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.receiver
+    }
+}
+
+/// Subscribes to mutations of the shared `lock`, receiving the new version after each
+pub fn subscribe<P: Deref + Clone>(lock: &P) -> Receiver<P> {
+    let key = key(&**lock);
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let (sender, receiver) = mpsc::channel();
+    with_subscribers(key, |subscribers| subscribers.senders.push((id, sender)));
+    Receiver {
+        receiver,
+        _subscription: Subscription {
+            key,
+            id: Some(id),
+            _lock: lock.clone(),
+        },
+    }
+}
+
+/// Subscribes to mutations of the shared `lock` through a [tokio::sync::watch] channel holding the
+/// latest version
+#[cfg(feature = "tokio")]
+pub fn subscribe_async<P: Deref + Clone>(lock: &P) -> AsyncReceiver<P> {
+    let key = key(&**lock);
+    let receiver = with_subscribers(key, |subscribers| {
+        subscribers.watchers += 1;
+        match &subscribers.watch {
+            Some(watch) => watch.subscribe(),
+            None => {
+                let (watch, receiver) = tokio::sync::watch::channel(subscribers.version);
+                subscribers.watch = Some(watch);
+                receiver
+            }
+        }
+    });
+    AsyncReceiver {
+        receiver,
+        _subscription: Subscription {
+            key,
+            id: None,
+            _lock: lock.clone(),
+        },
+    }
+}
+
+fn notify(key: usize) {
+    let mut subscribers = SUBSCRIBERS.lock().unwrap_or_else(PoisonError::into_inner);
+    let Some(entry) = subscribers.iter_mut().find(|entry| entry.key == key) else {
+        return;
+    };
+
+    entry.version += 1;
+    let version = entry.version;
+    for (_, sender) in &entry.senders {
+        let _ = sender.send(version);
+    }
+    #[cfg(feature = "tokio")]
+    if let Some(watch) = &entry.watch {
+        watch.send_replace(version);
+    }
+}
+
+/// A guard of a watched field, notifying the subscribers of the lock after being released if it
+/// was mutably dereferenced
+pub struct Watched<G> {
+    guard: G,
+    token: WatchToken,
+}
+
+/// Notifies the subscribers of a lock when dropped, if the guard was mutated
+struct WatchToken {
+    key: usize,
+    mutated: bool,
+}
+
+impl<G> Watched<G> {
+    pub fn new(guard: G, key: usize) -> Self {
+        Watched {
+            guard,
+            token: WatchToken {
+                key,
+                mutated: false,
+            },
+        }
+    }
+}
+
+impl<G: Deref> Deref for Watched<G> {
+    type Target = G::Target;
+
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+impl<G: DerefMut> DerefMut for Watched<G> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.token.mutated = true;
+        &mut self.guard
+    }
+}
+
+impl Drop for WatchToken {
+    fn drop(&mut self) {
+        if self.mutated {
+            notify(self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn is_subscribed(key: usize) -> bool {
+        let subscribers = SUBSCRIBERS.lock().unwrap_or_else(PoisonError::into_inner);
+        subscribers.iter().any(|entry| entry.key == key)
+    }
+
+    #[test]
+    fn notifies_every_subscriber() {
+        let lock = Arc::new(Mutex::new(0));
+        let first = subscribe(&lock);
+        let second = subscribe(&lock);
+
+        notify(key(&*lock));
+        notify(key(&*lock));
+
+        assert_eq!(first.try_iter().collect::<Vec<_>>(), [1, 2]);
+        assert_eq!(second.try_iter().collect::<Vec<_>>(), [1, 2]);
+    }
+
+    #[test]
+    fn dropping_the_last_receiver_removes_the_subscription() {
+        let lock = Arc::new(Mutex::new(0));
+        let first = subscribe(&lock);
+        let second = subscribe(&lock);
+
+        drop(first);
+        assert!(is_subscribed(key(&*lock)));
+        drop(second);
+        assert!(!is_subscribed(key(&*lock)));
+    }
+
+    #[test]
+    fn receiver_keeps_the_lock_alive() {
+        let lock = Arc::new(Mutex::new(0));
+        let receiver = subscribe(&lock);
+        let weak = Arc::downgrade(&lock);
+
+        drop(lock);
+        assert!(weak.upgrade().is_some());
+        drop(receiver);
+        assert!(weak.upgrade().is_none());
+    }
+}
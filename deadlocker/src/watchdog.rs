@@ -0,0 +1,372 @@
+//! Tracking of the guard sets produced by generated lockers, with a watchdog reporting stalls
+//!
+//! Every generated `lock` method registers its guard set, recording the owning thread, the caller
+//! location, and for each field whether it is being waited on or held and since when. A
+//! [snapshot] of all registered guard sets may be taken at any time, and a [Watchdog] may be
+//! spawned to pass a snapshot to a callback whenever an acquisition has been waiting for longer
+//! than a threshold.
+
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    panic::Location,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    thread::{self, JoinHandle, ThreadId},
+    time::{Duration, Instant},
+};
+
+use crate::LockFieldInfo;
+
+struct FieldEntry {
+    field: LockFieldInfo,
+    since: Instant,
+    held: bool,
+    reported: bool,
+}
+
+struct Entry {
+    struct_name: &'static str,
+    thread_id: ThreadId,
+    thread_name: Option<String>,
+    location: &'static Location<'static>,
+    fields: Vec<Option<FieldEntry>>,
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+static REGISTRY: Mutex<Option<HashMap<u64, Entry>>> = Mutex::new(None);
+
+fn with_registry<R>(f: impl FnOnce(&mut HashMap<u64, Entry>) -> R) -> R {
+    let mut registry = REGISTRY.lock().unwrap_or_else(PoisonError::into_inner);
+    f(registry.get_or_insert_with(HashMap::new))
+}
+
+/// A registered guard set, which is unregistered once all of its guards are released
+pub struct GuardSet {
+    id: u64,
+}
+
+impl Drop for GuardSet {
+    fn drop(&mut self) {
+        with_registry(|registry| registry.remove(&self.id));
+    }
+}
+
+/// Registers a guard set of `struct_name` being locked at `location`
+pub fn begin(struct_name: &'static str, location: &'static Location<'static>) -> Arc<GuardSet> {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+
+    let thread = thread::current();
+    let entry = Entry {
+        struct_name,
+        thread_id: thread.id(),
+        thread_name: thread.name().map(str::to_owned),
+        location,
+        fields: Vec::new(),
+    };
+    with_registry(|registry| registry.insert(id, entry));
+
+    Arc::new(GuardSet { id })
+}
+
+/// Registers that a lock of `field` is being waited on, to be called right before acquiring it
+pub fn wait(set: &Arc<GuardSet>, field: &LockFieldInfo) -> FieldToken {
+    let index = with_registry(|registry| {
+        let entry = registry.get_mut(&set.id)?;
+        entry.fields.push(Some(FieldEntry {
+            field: *field,
+            since: Instant::now(),
+            held: false,
+            reported: false,
+        }));
+        Some(entry.fields.len() - 1)
+    });
+
+    FieldToken {
+        set: Arc::clone(set),
+        index,
+    }
+}
+
+/// The registration of a single lock in a guard set, which is released when dropped
+pub struct FieldToken {
+    set: Arc<GuardSet>,
+    index: Option<usize>,
+}
+
+impl FieldToken {
+    /// Registers that the lock has been acquired
+    pub fn acquired(&self) {
+        self.update(|field| {
+            if let Some(field) = field {
+                field.since = Instant::now();
+                field.held = true;
+            }
+        });
+    }
+
+    fn update(&self, f: impl FnOnce(&mut Option<FieldEntry>)) {
+        if let Some(index) = self.index {
+            with_registry(|registry| {
+                if let Some(entry) = registry.get_mut(&self.set.id) {
+                    f(&mut entry.fields[index]);
+                }
+            });
+        }
+    }
+}
+
+impl Drop for FieldToken {
+    fn drop(&mut self) {
+        self.update(|field| *field = None);
+    }
+}
+
+/// The state of a single lock in a [GuardSetSnapshot]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldState {
+    /// The lock has been waited on for the given duration
+    Waiting(Duration),
+    /// The lock has been held for the given duration
+    Held(Duration),
+}
+
+/// A single lock in a [GuardSetSnapshot]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldSnapshot {
+    pub field: LockFieldInfo,
+    pub state: FieldState,
+}
+
+/// A registered guard set at the time a [Snapshot] was taken
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GuardSetSnapshot {
+    pub struct_name: &'static str,
+    pub thread_id: ThreadId,
+    pub thread_name: Option<String>,
+    pub location: &'static Location<'static>,
+    pub fields: Vec<FieldSnapshot>,
+}
+
+/// All registered guard sets at a point in time
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub guard_sets: Vec<GuardSetSnapshot>,
+}
+
+impl Display for Snapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for set in &self.guard_sets {
+            writeln!(
+                f,
+                "{} locked at {} by thread {} ({:?}):",
+                set.struct_name,
+                set.location,
+                set.thread_name.as_deref().unwrap_or("<unnamed>"),
+                set.thread_id
+            )?;
+            for field in &set.fields {
+                match field.state {
+                    FieldState::Waiting(duration) => {
+                        writeln!(f, "  waiting on {} for {:?}", field.field, duration)?
+                    }
+                    FieldState::Held(duration) => {
+                        writeln!(f, "  holding {} for {:?}", field.field, duration)?
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Takes a [Snapshot] of all registered guard sets
+pub fn snapshot() -> Snapshot {
+    let now = Instant::now();
+    let mut guard_sets: Vec<(u64, GuardSetSnapshot)> = with_registry(|registry| {
+        registry
+            .iter()
+            .map(|(id, entry)| {
+                let fields = entry
+                    .fields
+                    .iter()
+                    .flatten()
+                    .map(|field| FieldSnapshot {
+                        field: field.field,
+                        state: if field.held {
+                            FieldState::Held(now - field.since)
+                        } else {
+                            FieldState::Waiting(now - field.since)
+                        },
+                    })
+                    .collect();
+
+                let set = GuardSetSnapshot {
+                    struct_name: entry.struct_name,
+                    thread_id: entry.thread_id,
+                    thread_name: entry.thread_name.clone(),
+                    location: entry.location,
+                    fields,
+                };
+                (*id, set)
+            })
+            .collect()
+    });
+
+    guard_sets.sort_by_key(|(id, _)| *id);
+    Snapshot {
+        guard_sets: guard_sets.into_iter().map(|(_, set)| set).collect(),
+    }
+}
+
+/// Marks all acquisitions waiting for longer than `threshold` as reported, returning whether any
+/// had not been reported before
+fn stalled(threshold: Duration) -> bool {
+    let now = Instant::now();
+    let mut stalled = false;
+    with_registry(|registry| {
+        for field in registry
+            .values_mut()
+            .flat_map(|entry| entry.fields.iter_mut().flatten())
+        {
+            if !field.held && !field.reported && now - field.since > threshold {
+                field.reported = true;
+                stalled = true;
+            }
+        }
+    });
+    stalled
+}
+
+type Callback = Box<dyn Fn(&Snapshot) + Send>;
+
+/// Configuration of a thread checking for acquisitions waiting for longer than a threshold
+pub struct Watchdog {
+    threshold: Duration,
+    interval: Duration,
+    callback: Callback,
+}
+
+impl Watchdog {
+    /// Creates a watchdog reporting acquisitions waiting for longer than `threshold`, by default
+    /// checking at a quarter of the threshold and logging snapshots through [log::error]
+    pub fn new(threshold: Duration) -> Self {
+        Watchdog {
+            threshold,
+            interval: threshold / 4,
+            callback: Box::new(|snapshot| log::error!("Lock acquisition stalled:\n{}", snapshot)),
+        }
+    }
+
+    /// Sets how often to check for stalled acquisitions
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Sets the function called with a [Snapshot] whenever an acquisition stalls
+    pub fn on_stall(mut self, callback: impl Fn(&Snapshot) + Send + 'static) -> Self {
+        self.callback = Box::new(callback);
+        self
+    }
+
+    /// Spawns the watchdog thread, which runs until the returned handle is dropped
+    pub fn spawn(self) -> WatchdogHandle {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = Arc::clone(&stop);
+            thread::Builder::new()
+                .name(String::from("deadlocker-watchdog"))
+                .spawn(move || {
+                    while !stop.load(Ordering::Acquire) {
+                        if stalled(self.threshold) {
+                            (self.callback)(&snapshot());
+                        }
+                        thread::park_timeout(self.interval);
+                    }
+                })
+                .expect("Failed to spawn watchdog thread")
+        };
+
+        WatchdogHandle {
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+/// Handle to a running [Watchdog], stopping it when dropped
+pub struct WatchdogHandle {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for WatchdogHandle {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LockKind;
+    use std::sync::mpsc;
+
+    fn info(field_name: &'static str) -> LockFieldInfo {
+        LockFieldInfo {
+            module_path: module_path!(),
+            struct_name: "WatchdogStore",
+            field_name,
+            rank: 0,
+            inner_type: "u32",
+            is_async: false,
+            is_result: false,
+            kind: LockKind::Single,
+        }
+    }
+
+    #[test]
+    fn stalled_acquisition_is_reported_with_its_holder() {
+        let config = info("config");
+        let holder = begin("WatchdogStore", Location::caller());
+        let held = wait(&holder, &config);
+        held.acquired();
+        let waiter = begin("WatchdogStore", Location::caller());
+        let _waiting = wait(&waiter, &config);
+
+        let (sender, receiver) = mpsc::channel();
+        let _watchdog = Watchdog::new(Duration::from_millis(20))
+            .interval(Duration::from_millis(5))
+            .on_stall(move |snapshot| {
+                let _ = sender.send(snapshot.clone());
+            })
+            .spawn();
+
+        let snapshot = receiver
+            .recv_timeout(Duration::from_secs(5))
+            .expect("Stall was not reported");
+        let states = snapshot
+            .guard_sets
+            .iter()
+            .flat_map(|set| &set.fields)
+            .filter(|field| field.field == config)
+            .map(|field| field.state)
+            .collect::<Vec<FieldState>>();
+
+        assert_eq!(states.len(), 2);
+        assert!(matches!(states[0], FieldState::Held(_)));
+        assert!(matches!(
+            states[1],
+            FieldState::Waiting(waited) if waited > Duration::from_millis(20)
+        ));
+        assert!(snapshot.to_string().contains("waiting on WatchdogStore.config"));
+        assert!(snapshot.to_string().contains("holding WatchdogStore.config"));
+    }
+}
@@ -0,0 +1,91 @@
+use deadlocker::Locker;
+use std::sync::{Arc, Mutex};
+
+#[derive(Locker)]
+pub struct Session {
+    #[result]
+    pub user: Arc<Mutex<String>>,
+    #[result]
+    pub token: Arc<Mutex<u64>>,
+}
+
+#[derive(Locker)]
+pub struct App {
+    #[result]
+    pub name: Arc<Mutex<String>>,
+    #[result]
+    #[locker(collection)]
+    pub shards: Vec<Arc<Mutex<u32>>>,
+    #[locker(flatten(user, token))]
+    pub session: Session,
+}
+
+fn app() -> App {
+    App {
+        name: Arc::new(Mutex::new("app".to_string())),
+        shards: (0..4).map(|shard| Arc::new(Mutex::new(shard))).collect(),
+        session: Session {
+            user: Arc::new(Mutex::new("user".to_string())),
+            token: Arc::new(Mutex::new(7)),
+        },
+    }
+}
+
+fn describe<'a>(lock: &impl AppHasName<'a>) -> String {
+    lock.name().to_string()
+}
+
+fn total<'a>(lock: &impl AppHasShards<'a>) -> u32 {
+    lock.shards().iter().map(|(_, shard)| *shard).sum()
+}
+
+fn user<'a>(lock: &impl AppHasSessionUnderscoreUser<'a>) -> usize {
+    lock.session_user().len()
+}
+
+fn rename<'a>(lock: &mut impl AppHasName<'a>) {
+    lock.name_mut().push_str("-renamed");
+}
+
+fn rotate<'a>(lock: &mut (impl AppHasShards<'a> + AppHasSessionUnderscoreToken<'a>)) {
+    *lock.session_token_mut() += 1;
+    lock.shards_mut()[2] += 1;
+}
+
+#[test]
+fn reading_through_helpers_leaves_fields_clean() {
+    let mut app = app();
+    let lock = app
+        .locker()
+        .name()
+        .shards([1, 2])
+        .session_user()
+        .lock()
+        .unwrap();
+
+    assert_eq!(describe(&lock), "app");
+    assert_eq!(total(&lock), 3);
+    assert_eq!(user(&lock), 4);
+    assert!(lock.dirty_fields().is_empty());
+}
+
+#[test]
+fn writing_through_helpers_marks_fields_dirty() {
+    let mut app = app();
+    let mut lock = app
+        .locker()
+        .name()
+        .shards([1, 2])
+        .session_token()
+        .session_user()
+        .lock()
+        .unwrap();
+
+    rename(&mut lock);
+    assert_eq!(lock.dirty_fields(), ["name"]);
+
+    rotate(&mut lock);
+    assert_eq!(lock.dirty_fields(), ["name", "shards", "session"]);
+    assert_eq!(lock.shards.dirty_keys().collect::<Vec<_>>(), [&2]);
+    assert_eq!(describe(&lock), "app-renamed");
+}
@@ -1,12 +1,16 @@
 use quote::ToTokens;
+use syn::{punctuated::Punctuated, Token};
 
-use crate::path::PathAugment;
+use crate::{path::PathAugment, LOCKER};
 
 pub trait AttributeAugment {
     /// Extracts the value in a path-style attribute such as in `#[inner_type = "usize"]`
     fn extract_val(&self) -> String;
 
     fn str_equals(&self, str: &str) -> bool;
+
+    /// Returns the arguments of a list-style attribute such as in `#[locker(flatten(user))]`
+    fn locker_args(&self) -> Vec<syn::Meta>;
 }
 
 impl AttributeAugment for syn::Attribute {
@@ -24,4 +28,94 @@ impl AttributeAugment for syn::Attribute {
     fn str_equals(&self, str: &str) -> bool {
         self.path().str_equals(str)
     }
+
+    fn locker_args(&self) -> Vec<syn::Meta> {
+        if !self.str_equals(LOCKER) {
+            return Vec::new();
+        }
+
+        self.parse_args_with(Punctuated::<syn::Meta, Token![,]>::parse_terminated)
+            .expect("Expected a comma separated list of options")
+            .into_iter()
+            .collect()
+    }
+}
+
+/// Helper functions for collections of [syn::Attribute]s
+pub trait AttributesAugment {
+    /// Returns the first argument named `name` across all `#[locker(...)]` attributes
+    fn locker_arg(&self, name: &str) -> Option<syn::Meta>;
+
+    /// Returns the string value of the first argument named `name`, as in `#[locker(name = "..")]`
+    fn locker_str(&self, name: &str) -> Option<String>;
+
+    /// Returns the integer value of the first argument named `name`, as in `#[locker(name = 1)]`
+    fn locker_int(&self, name: &str) -> Option<u32>;
+}
+
+impl AttributesAugment for [syn::Attribute] {
+    fn locker_arg(&self, name: &str) -> Option<syn::Meta> {
+        self.iter()
+            .flat_map(AttributeAugment::locker_args)
+            .find(|arg| arg.path().str_equals(name))
+    }
+
+    fn locker_str(&self, name: &str) -> Option<String> {
+        match self.locker_arg(name)? {
+            syn::Meta::NameValue(syn::MetaNameValue {
+                value:
+                    syn::Expr::Lit(syn::ExprLit {
+                        lit: syn::Lit::Str(value),
+                        ..
+                    }),
+                ..
+            }) => Some(value.value()),
+            _ => panic!("Expected a string value, as in {} = \"...\"", name),
+        }
+    }
+
+    fn locker_int(&self, name: &str) -> Option<u32> {
+        match self.locker_arg(name)? {
+            syn::Meta::NameValue(syn::MetaNameValue {
+                value:
+                    syn::Expr::Lit(syn::ExprLit {
+                        lit: syn::Lit::Int(value),
+                        ..
+                    }),
+                ..
+            }) => Some(
+                value
+                    .base10_parse()
+                    .unwrap_or_else(|_| panic!("Expected a non-negative integer for {}", name)),
+            ),
+            _ => panic!("Expected an integer value, as in {} = 1", name),
+        }
+    }
+}
+
+/// Parses a duration such as `50ms` into nanoseconds
+///
+/// Supported units are `ns`, `us`, `ms`, `s` and `m`
+pub fn parse_duration(duration: &str) -> u64 {
+    let duration = duration.trim();
+    let split = duration
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(duration.len());
+    let (value, unit) = duration.split_at(split);
+
+    let value: u64 = value
+        .parse()
+        .unwrap_or_else(|_| panic!("Expected a duration such as \"50ms\", found \"{}\"", duration));
+    let factor = match unit.trim() {
+        "ns" => 1,
+        "us" | "µs" => 1_000,
+        "ms" => 1_000_000,
+        "s" => 1_000_000_000,
+        "m" => 60_000_000_000,
+        unit => panic!("Unknown duration unit \"{}\", expected one of ns, us, ms, s or m", unit),
+    };
+
+    value
+        .checked_mul(factor)
+        .unwrap_or_else(|| panic!("Duration \"{}\" is too long", duration))
 }
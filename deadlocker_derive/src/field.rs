@@ -1,11 +1,12 @@
-use proc_macro2::TokenStream;
-use quote::{quote, ToTokens};
+use proc_macro2::{Ident, TokenStream};
+use quote::{format_ident, quote, ToTokens};
 use regex::Regex;
-use syn::Field;
+use syn::{punctuated::Punctuated, Field, Token};
 
 use crate::{
-    attribute::AttributeAugment, ASYNC, DEFAULT_OUTER_TYPE, EXCLUDE, INCLUDE, INNER_TYPE,
-    LOCK_METHOD, OUTER_TYPE, RESULT,
+    attribute::{parse_duration, AttributeAugment, AttributesAugment},
+    ASYNC, COLLECTION, DEFAULT_OUTER_TYPE, EXCLUDE, FLATTEN, ID, INCLUDE, INNER_TYPE, KEYED, LEVEL,
+    LOCK_METHOD, OUTER_TYPE, RESULT, TRY_LOCK_METHOD, WARN_HOLD, WATCH,
 };
 
 /// Helper functions for the [syn::Field] type
@@ -22,13 +23,59 @@ pub trait FieldAugment {
     /// Returns whether the fields has a `exclude` attribute
     fn is_excluded(&self) -> bool;
 
+    /// Returns whether the fields has a `collection` attribute
+    fn is_collection(&self) -> bool;
+
+    /// Returns whether the fields has a `keyed` attribute
+    fn is_keyed(&self) -> bool;
+
+    /// Returns whether the fields has an `id` attribute
+    fn is_id(&self) -> bool;
+
+    /// Returns whether the field is an `Arc` or `Rc`, through which the lock is shared
+    fn is_shared(&self) -> bool;
+
+    /// Returns whether the fields has a `watch` attribute
+    fn is_watched(&self) -> bool;
+
     /// Returns the method for locking the outer lock
     fn lock_method(&self) -> TokenStream;
 
+    /// Returns the method attempting to lock the field without blocking
+    ///
+    /// Unless set through `try_lock_method`, this is the lock method prefixed by `try_`, such as
+    /// `try_lock()` for both `lock()` and `lock().await`
+    fn try_lock_method(&self) -> TokenStream;
+
+    /// Returns the threshold in nanoseconds above which holding the field is warned about, set
+    /// through `warn_hold` on the field or else on the struct
+    fn warn_hold(&self, struct_attrs: &[syn::Attribute]) -> Option<u64>;
+
+    /// Returns the lock level of the field, set through `level` on the field or else on the
+    /// struct
+    fn level(&self, struct_attrs: &[syn::Attribute]) -> Option<u32>;
+
     /// Returns the inner type for the field
     ///
     /// This is what the user is attempting to interact with through the lock
     fn return_type(&self) -> syn::TypePath;
+
+    /// Returns the fields listed in a `#[locker(flatten(...))]` attribute, if present
+    fn flattened(&self) -> Option<Vec<Ident>>;
+
+    /// Returns the type of the individual locks in a `collection` or `keyed` field
+    fn element_type(&self) -> Option<syn::Type>;
+
+    /// Returns the type used to select elements from a `collection` or `keyed` field
+    fn selection_type(&self) -> Option<syn::Type>;
+
+    /// Returns the identifier of the builder field storing the selected elements, if any
+    fn selection_ident(&self) -> Option<Ident>;
+
+    /// Returns the path to a type generated by the derive macro on the field's type
+    ///
+    /// Used for flattened fields, where `Session` becomes `Session{suffix}`
+    fn nested_path(&self, suffix: &str) -> syn::Path;
 }
 
 impl FieldAugment for Field {
@@ -68,6 +115,33 @@ impl FieldAugment for Field {
         false
     }
 
+    fn is_collection(&self) -> bool {
+        self.attrs.locker_arg(COLLECTION).is_some()
+    }
+
+    fn is_keyed(&self) -> bool {
+        self.attrs.locker_arg(KEYED).is_some()
+    }
+
+    fn is_id(&self) -> bool {
+        self.attrs.locker_arg(ID).is_some()
+    }
+
+    fn is_shared(&self) -> bool {
+        match &self.ty {
+            syn::Type::Path(type_path) => type_path
+                .path
+                .segments
+                .last()
+                .map_or(false, |segment| segment.ident == "Arc" || segment.ident == "Rc"),
+            _ => false,
+        }
+    }
+
+    fn is_watched(&self) -> bool {
+        self.attrs.locker_arg(WATCH).is_some()
+    }
+
     fn lock_method(&self) -> TokenStream {
         let mut lock_method = None;
         for attr in &self.attrs {
@@ -91,9 +165,41 @@ impl FieldAugment for Field {
         }
     }
 
+    fn try_lock_method(&self) -> TokenStream {
+        let method = self.attrs.locker_str(TRY_LOCK_METHOD).unwrap_or_else(|| {
+            format!(
+                "try_{}",
+                self.lock_method()
+                    .to_string()
+                    .replace(' ', "")
+                    .trim_end_matches(".await")
+            )
+        });
+
+        syn::parse_str::<TokenStream>(&method).expect("Failed to parse try lock method")
+    }
+
+    fn warn_hold(&self, struct_attrs: &[syn::Attribute]) -> Option<u64> {
+        self.attrs
+            .locker_str(WARN_HOLD)
+            .or_else(|| struct_attrs.locker_str(WARN_HOLD))
+            .map(|duration| parse_duration(&duration))
+    }
+
+    fn level(&self, struct_attrs: &[syn::Attribute]) -> Option<u32> {
+        self.attrs
+            .locker_int(LEVEL)
+            .or_else(|| struct_attrs.locker_int(LEVEL))
+    }
+
     fn return_type(&self) -> syn::TypePath {
         let mut outer_type: String = DEFAULT_OUTER_TYPE.to_string();
-        let path = self.ty.to_token_stream().to_string().replace(' ', "");
+        let path = self
+            .element_type()
+            .unwrap_or_else(|| self.ty.to_owned())
+            .to_token_stream()
+            .to_string()
+            .replace(' ', "");
 
         for attr in &self.attrs {
             if attr.str_equals(INNER_TYPE) {
@@ -112,4 +218,92 @@ impl FieldAugment for Field {
 
         panic!("Could not find inner type by removing outer type")
     }
+
+    fn flattened(&self) -> Option<Vec<Ident>> {
+        let nested = match self.attrs.locker_arg(FLATTEN)? {
+            syn::Meta::List(list) => list
+                .parse_args_with(Punctuated::<Ident, Token![,]>::parse_terminated)
+                .expect("Expected a comma separated list of field names"),
+            _ => panic!("Flattened fields must list the fields to include, as in flatten(foo, bar)"),
+        };
+
+        if nested.is_empty() {
+            panic!("Flattened fields must list at least one field");
+        }
+
+        Some(nested.into_iter().collect())
+    }
+
+    fn element_type(&self) -> Option<syn::Type> {
+        if self.is_keyed() {
+            type_arguments(&self.ty).get(1).cloned().or_else(|| {
+                panic!("Keyed fields must be of the form HashMap<K, T> or BTreeMap<K, T>")
+            })
+        } else if self.is_collection() {
+            type_arguments(&self.ty)
+                .first()
+                .cloned()
+                .or_else(|| panic!("Collection fields must be of the form Vec<T>"))
+        } else {
+            None
+        }
+    }
+
+    fn selection_type(&self) -> Option<syn::Type> {
+        if self.is_keyed() {
+            type_arguments(&self.ty).first().cloned()
+        } else if self.is_collection() {
+            Some(syn::parse_quote! {usize})
+        } else {
+            None
+        }
+    }
+
+    fn selection_ident(&self) -> Option<Ident> {
+        self.selection_type()?;
+        Some(format_ident!(
+            "_{}_selection",
+            self.ident.as_ref().expect("Fields must be named")
+        ))
+    }
+
+    fn nested_path(&self, suffix: &str) -> syn::Path {
+        let mut path = match &self.ty {
+            syn::Type::Path(type_path) => type_path.path.clone(),
+            _ => panic!("Flattened fields must be a struct deriving Locker"),
+        };
+
+        let last = path.segments.last_mut().expect("Expected type");
+        if !last.arguments.is_empty() {
+            panic!("Flattened fields can not have generic arguments");
+        }
+        last.ident = format_ident!("{}{}", last.ident, suffix);
+
+        path
+    }
+}
+
+/// Returns the generic type arguments of the last segment of a type path, such as `K` and `T` in
+/// `HashMap<K, T>`
+fn type_arguments(ty: &syn::Type) -> Vec<syn::Type> {
+    let arguments = match ty {
+        syn::Type::Path(type_path) => type_path
+            .path
+            .segments
+            .last()
+            .map(|segment| &segment.arguments),
+        _ => None,
+    };
+
+    match arguments {
+        Some(syn::PathArguments::AngleBracketed(arguments)) => arguments
+            .args
+            .iter()
+            .filter_map(|argument| match argument {
+                syn::GenericArgument::Type(ty) => Some(ty.to_owned()),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
 }
@@ -2,7 +2,14 @@ use proc_macro2::{Ident, TokenStream};
 use quote::{format_ident, quote, ToTokens};
 use syn::Field;
 
-use crate::{field::FieldAugment, state::State};
+use crate::{
+    attribute::AttributesAugment,
+    field::FieldAugment,
+    hooks::{field_info, stats_fields, Hooks},
+    lockable::{ordered_fields, Lockable},
+    state::{dirty_ident, pascal_case, sources_ident, State},
+    DEBUG, SERDE, SNAPSHOT, TRANSACTION,
+};
 
 /// Generate the token stream for the builder struct definition
 pub fn generate_builder_struct(
@@ -18,6 +25,14 @@ pub fn generate_builder_struct(
         field_declarations.push(quote! {
             #field_ident: &'a mut #ty
         });
+
+        if let (Some(selection), Some(selection_type)) =
+            (field.selection_ident(), field.selection_type())
+        {
+            field_declarations.push(quote! {
+                #selection: Vec<#selection_type>
+            });
+        }
     }
 
     quote! {
@@ -41,31 +56,61 @@ pub fn generate_impl_for_all_states(
         let mut functions = Vec::new();
         for complement in &state.complements {
             let complement_state = state.add_state(complement);
-            let complement_fn = format_ident!(
-                "{}",
-                complement.ident.as_ref().expect("Fields must be named")
-            );
+            let complement_fn = complement.method_ident();
             let complement_ident = complement_state.ident();
             let fields_iter = all_fields.iter().map(|f| &f.ident);
             let fields_iter2 = all_fields.iter().map(|f| &f.ident);
 
+            let complement_selection = complement.field.selection_ident();
+            let selections = all_fields.iter().filter_map(|f| {
+                let selection = f.selection_ident()?;
+                if Some(&selection) == complement_selection.as_ref() {
+                    Some(quote! {
+                        #selection: selection
+                            .into_iter()
+                            .map(|key| std::borrow::Borrow::borrow(&key).to_owned())
+                            .collect()
+                    })
+                } else {
+                    Some(quote! {#selection: self.#selection})
+                }
+            });
+
+            let (generics, arguments, bounds) = match complement.field.selection_type() {
+                Some(selection_type) => (
+                    quote! {<I>},
+                    quote! {, selection: I},
+                    quote! {
+                        where
+                            I: IntoIterator,
+                            I::Item: std::borrow::Borrow<#selection_type>,
+                    },
+                ),
+                None => (quote! {}, quote! {}, quote! {}),
+            };
+
             functions.push(quote! {
-                pub fn #complement_fn(self) -> #locker_struct_name<'a, #complement_ident<'a>> {
+                #[track_caller]
+                pub fn #complement_fn #generics(self #arguments) -> #locker_struct_name<'a, #complement_ident<'a>>
+                #bounds
+                {
                     #locker_struct_name{
                         _phantom: std::marker::PhantomData,
-                        #(#fields_iter: self.#fields_iter2),*
+                        #(#fields_iter: self.#fields_iter2,)*
+                        #(#selections),*
                     }
                 }
             });
         }
 
         let idents: Vec<&Ident> = state
-            .active
+            .groups()
             .iter()
-            .map(|a| a.ident.as_ref().expect("All fields must be named"))
+            .map(|g| g.field.ident.as_ref().expect("All fields must be named"))
             .collect();
 
-        let locked_fields = state.locked_fields();
+        let locked_fields = state.locked_fields(false);
+        let sources = state.source_idents();
 
         let parameters = if !state.active.is_empty() {
             quote! {<'a>}
@@ -73,14 +118,8 @@ pub fn generate_impl_for_all_states(
             quote! {}
         };
 
-        let asyncrocity = if state.is_async() {
-            quote! {async}
-        } else {
-            quote! {}
-        };
-
         let (result_left, result_right, return_statement) =
-            if state.active.iter().any(|f| f.is_result()) {
+            if state.is_fallible() {
                 (
                     quote! {
                         Result<
@@ -90,18 +129,129 @@ pub fn generate_impl_for_all_states(
                     },
                     quote! {
                         Ok(
-                        #state_ident{#(#idents),*}
+                        #state_ident{#(#idents,)* _acquired_at: __location, _sources: (#(#sources,)*)}
                         )
                     },
                 )
             } else {
-                (quote! {}, quote! {}, quote! {#state_ident{#(#idents),*}})
+                (
+                    quote! {},
+                    quote! {},
+                    quote! {#state_ident{#(#idents,)* _acquired_at: __location, _sources: (#(#sources,)*)}},
+                )
             };
-        let lock_method = if !state.active.is_empty() {
+        let hooks = Hooks::for_state(state);
+        let setup = hooks.setup(state);
+        let finish = hooks.finish();
+        let body = hooks.instrument(
             quote! {
-                pub #asyncrocity fn lock(self) -> #result_left #state_ident #parameters #result_right {
-                    #(#locked_fields)*
-                    #return_statement
+                #(#locked_fields)*
+                #finish
+                #return_statement
+            },
+            state.is_async(),
+        );
+        // Structs with levels lock through a wrapper taking and returning a token
+        let levels = state.levels();
+        let (lock_ident, hidden) = match levels {
+            Some(_) => (format_ident!("__lock"), quote! {#[doc(hidden)]}),
+            None => (format_ident!("lock"), quote! {}),
+        };
+        let lock_method = if state.active.is_empty() {
+            quote! {}
+        } else if !state.is_async() {
+            quote! {
+                #hidden
+                #[track_caller]
+                pub fn #lock_ident(self) -> #result_left #state_ident #parameters #result_right {
+                    let __location = std::panic::Location::caller();
+                    #setup
+                    #body
+                }
+            }
+        } else {
+            // The caller's location is only available outside of the returned future
+            quote! {
+                #hidden
+                #[track_caller]
+                pub fn #lock_ident(self) -> impl std::future::Future<Output = #result_left #state_ident #parameters #result_right> + 'a {
+                    let __location = std::panic::Location::caller();
+                    #setup
+                    #body
+                }
+            }
+        };
+        let leveled_lock_method = match levels {
+            Some((min, max)) if !state.active.is_empty() => {
+                let min = format_ident!("L{}", min);
+                let max = format_ident!("L{}", max);
+                let token = quote! {::deadlocker::LockToken<'a, ::deadlocker::level::#max>};
+                let (output, acquired) = if state.is_fallible() {
+                    (
+                        quote! {Result<(#state_ident<'a>, #token), Box<dyn std::error::Error + 'a>>},
+                        quote! {locked.map(|state| (state, token))},
+                    )
+                } else {
+                    (
+                        quote! {(#state_ident<'a>, #token)},
+                        quote! {(locked, token)},
+                    )
+                };
+                let bounds = quote! {
+                    where
+                        L: ::deadlocker::level::Level,
+                        ::deadlocker::level::#min: ::deadlocker::level::LockAfter<L>,
+                        ::deadlocker::level::#max: ::deadlocker::level::LockAfter<L>,
+                };
+
+                if state.is_async() {
+                    quote! {
+                        /// Acquires the locks while holding `token`, yielding a token of the highest
+                        /// level acquired
+                        #[track_caller]
+                        pub fn lock<L>(self, token: &'a mut ::deadlocker::LockToken<'_, L>) -> impl std::future::Future<Output = #output> + 'a
+                        #bounds
+                        {
+                            let token = token.raise::<::deadlocker::level::#max>();
+                            let locking = self.__lock();
+                            async move {
+                                let locked = locking.await;
+                                #acquired
+                            }
+                        }
+                    }
+                } else {
+                    quote! {
+                        /// Acquires the locks while holding `token`, yielding a token of the highest
+                        /// level acquired
+                        #[track_caller]
+                        pub fn lock<L>(self, token: &'a mut ::deadlocker::LockToken<'_, L>) -> #output
+                        #bounds
+                        {
+                            let token = token.raise::<::deadlocker::level::#max>();
+                            let locked = self.__lock();
+                            #acquired
+                        }
+                    }
+                }
+            }
+            _ => quote! {},
+        };
+
+        let nested_lock_methods = if !state.active.is_empty() {
+            generate_nested_lock_methods(state, &lock_ident)
+        } else {
+            quote! {}
+        };
+
+        let lock_trait = if !state.active.is_empty() && !state.is_async() && levels.is_none() {
+            quote! {
+                impl<'a> ::deadlocker::Lock for #locker_struct_name<'a, #state_ident #parameters> {
+                    type Output = #result_left #state_ident #parameters #result_right;
+                    #[track_caller]
+                    fn lock(self) -> Self::Output {
+                        self.lock()
+                    }
                 }
             }
         } else {
@@ -113,7 +263,13 @@ pub fn generate_impl_for_all_states(
                 #(#functions)*
 
                 #lock_method
+
+                #leveled_lock_method
+
+                #nested_lock_methods
             }
+
+            #lock_trait
         }
         .to_tokens(&mut impl_states)
     }
@@ -121,6 +277,50 @@ pub fn generate_impl_for_all_states(
     impl_states
 }
 
+/// Generate the token stream for the lock methods used when the struct is flattened into another
+///
+/// These always return a [Result], and the asynchronous variant is available regardless of
+/// whether the state contains asynchronous locks, as the flattening struct can not know either.
+/// Lock levels of the nested struct are not enforced, as the flattening struct has its own.
+fn generate_nested_lock_methods(state: &State, lock_ident: &Ident) -> TokenStream {
+    let state_ident = state.ident();
+    let return_type = quote! {
+        Result<#state_ident<'a>, Box<dyn std::error::Error + 'a>>
+    };
+
+    let awaited = if state.is_async() {
+        quote! {self.#lock_ident().await}
+    } else {
+        quote! {self.#lock_ident()}
+    };
+    let awaited = if state.is_fallible() {
+        awaited
+    } else {
+        quote! {Ok(#awaited)}
+    };
+
+    let sync_method = if !state.is_async() {
+        quote! {
+            #[doc(hidden)]
+            #[track_caller]
+            pub fn __lock_nested_sync(self) -> #return_type {
+                #awaited
+            }
+        }
+    } else {
+        quote! {}
+    };
+
+    quote! {
+        #[doc(hidden)]
+        pub async fn __lock_nested(self) -> #return_type {
+            #awaited
+        }
+
+        #sync_method
+    }
+}
+
 /// Generate the token stream for the implementation of the `Locker` trait
 pub fn generate_trait_implementation(
     struct_identifier: &Ident,
@@ -131,12 +331,1224 @@ pub fn generate_trait_implementation(
     let struct_fields = all_fields
         .iter()
         .filter_map(|f| Some(format_ident!("{}", f.ident.as_ref()?)));
+    let selections = all_fields.iter().filter_map(FieldAugment::selection_ident);
 
     quote! {
         impl<'a> Locker<'a> for #struct_identifier {
             type LockBuilder=#locker_struct_name<'a, #empty_identifier>;
             fn locker(&'a mut self) -> Self::LockBuilder {
-                Self::LockBuilder{_phantom: std::marker::PhantomData,#(#struct_fields: &mut self.#struct_fields),*}
+                Self::LockBuilder{_phantom: std::marker::PhantomData,#(#struct_fields: &mut self.#struct_fields,)*#(#selections: Vec::new()),*}
+            }
+        }
+    }
+}
+
+/// Generate the token stream for the implementation of the `LockInstance` trait
+///
+/// Without a field marked with `id`, the address of the lock behind the first field is used, if
+/// it is shared through an `Arc` or `Rc`. Otherwise the trait is not implemented.
+pub fn generate_instance_implementation(
+    struct_identifier: &Ident,
+    all_fields: &syn::Fields,
+    all_ordered: &[Lockable],
+) -> TokenStream {
+    if let Some(id_field) = all_fields.iter().find(|f| f.is_id()) {
+        let ident = &id_field.ident;
+        let ty = &id_field.ty;
+        return quote! {
+            impl ::deadlocker::LockInstance for #struct_identifier {
+                type Key = #ty;
+                fn instance_key(&self) -> Self::Key {
+                    std::clone::Clone::clone(&self.#ident)
+                }
+            }
+        };
+    }
+
+    let first = match all_ordered.first() {
+        Some(first) if first.nested.is_none() && first.field.is_shared() => &first.field,
+        _ => return quote! {},
+    };
+    let ident = &first.ident;
+
+    quote! {
+        impl ::deadlocker::LockInstance for #struct_identifier {
+            type Key = usize;
+            fn instance_key(&self) -> Self::Key {
+                &*self.#ident as *const _ as *const () as usize
+            }
+        }
+    }
+}
+
+/// Generate the token stream for the `{Struct}Has{Field}` traits, implemented by every state
+/// holding the lock of the field
+///
+/// Nested fields of flattened structs get traits of their own, such as `AppStateHasSessionUser`,
+/// delegating to the traits of the nested struct
+pub fn generate_has_traits(
+    struct_identifier: &Ident,
+    all_ordered: &[Lockable],
+    states: &[State],
+) -> TokenStream {
+    let mut traits = TokenStream::new();
+
+    for lockable in all_ordered {
+        let method = lockable.method_ident();
+        let suffix = pascal_case(&method.to_string());
+        let trait_ident = format_ident!("{}Has{}", struct_identifier, suffix);
+        let target_ident = format_ident!("{}Has{}Target", struct_identifier, suffix);
+        let method_mut = format_ident!("{}_mut", method);
+        let field = &lockable.field;
+        let ident = lockable.ident();
+
+        let (target, access, access_mut) = match &lockable.nested {
+            Some(nested) => {
+                let nested_suffix = pascal_case(&nested.to_string());
+                let nested_trait = field.nested_path(&format!("Has{}", nested_suffix));
+                let nested_target = field.nested_path(&format!("Has{}Target", nested_suffix));
+                let nested_mut = format_ident!("{}_mut", nested);
+                (
+                    quote! {#nested_target<'a>},
+                    quote! {#nested_trait::#nested(&self.#ident)},
+                    quote! {#nested_trait::#nested_mut(&mut self.#ident)},
+                )
+            }
+            None => match field.selection_type() {
+                Some(selection_type) => {
+                    let return_type = field.return_type();
+                    (
+                        quote! {::deadlocker::Elements<'a, #selection_type, #return_type>},
+                        quote! {&self.#ident},
+                        quote! {&mut self.#ident},
+                    )
+                }
+                None => {
+                    let return_type = field.return_type();
+                    (
+                        quote! {#return_type},
+                        quote! {&**self.#ident},
+                        quote! {&mut **self.#ident},
+                    )
+                }
+            },
+        };
+
+        let doc = format!(
+            "Implemented by every state of `{}` holding the lock of `{}`",
+            struct_identifier, method
+        );
+        quote! {
+            #[doc(hidden)]
+            pub type #target_ident<'a> = #target;
+
+            #[doc = #doc]
+            pub trait #trait_ident<'a> {
+                /// Returns the guarded value, without marking the field as mutably accessed
+                fn #method(&self) -> &#target_ident<'a>;
+
+                /// Returns the guarded value mutably, marking the field as mutably accessed
+                fn #method_mut(&mut self) -> &mut #target_ident<'a>;
+            }
+        }
+        .to_tokens(&mut traits);
+
+        for state in states.iter().filter(|state| state.active.contains(lockable)) {
+            let state_ident = state.ident();
+            quote! {
+                impl<'a> #trait_ident<'a> for #state_ident<'a> {
+                    fn #method(&self) -> &#target_ident<'a> {
+                        #access
+                    }
+
+                    fn #method_mut(&mut self) -> &mut #target_ident<'a> {
+                        #access_mut
+                    }
+                }
+            }
+            .to_tokens(&mut traits);
+        }
+    }
+
+    traits
+}
+
+/// Generate the token stream for the `unlocked` and `wait_until` methods of every state, releasing
+/// the locks while running a closure or waiting for a notification, along with the hidden methods
+/// reacquiring the locks
+pub fn generate_unlocked_implementations(states: &[State]) -> TokenStream {
+    let mut implementations = TokenStream::new();
+
+    for state in states.iter().filter(|state| !state.active.is_empty()) {
+        let state_ident = state.ident();
+        let sources_ident = sources_ident(&state_ident);
+        let dirty_ident = dirty_ident(&state_ident);
+        let idents = state.groups().into_iter().map(|group| &group.field.ident);
+        let sources = state.source_idents();
+        let locked_fields = state.locked_fields(true);
+        let location = quote! {&'static std::panic::Location<'static>};
+
+        let hooks = Hooks::for_state(state);
+        let setup = hooks.setup(state);
+        let finish = hooks.finish();
+        let state_expression = quote! {
+            #state_ident{#(#idents,)* _acquired_at: __location, _sources: (#(#sources,)*)}
+        };
+        let (output, body) = if state.is_fallible() {
+            (
+                quote! {Result<Self, Box<dyn std::error::Error + 'a>>},
+                quote! {Ok(#state_expression)},
+            )
+        } else {
+            (quote! {Self}, state_expression)
+        };
+        let body = hooks.instrument(
+            quote! {
+                #(#locked_fields)*
+                #finish
+                #body
+            },
+            state.is_async(),
+        );
+
+        let restore_dirty = |relocked: TokenStream| {
+            if state.is_fallible() {
+                quote! {
+                    #relocked.map(|mut state| {
+                        state.__restore_dirty(__dirty);
+                        state
+                    })
+                }
+            } else {
+                quote! {{
+                    let mut state = #relocked;
+                    state.__restore_dirty(__dirty);
+                    state
+                }}
+            }
+        };
+        let (relock, relocked, relocked_async, awaited) = if state.is_async() {
+            (
+                quote! {
+                    fn __relock(__sources: #sources_ident<'a>, __location: #location) -> impl std::future::Future<Output = #output> + 'a {
+                        let (#(#sources,)*) = __sources;
+                        #setup
+                        #body
+                    }
+                },
+                None,
+                restore_dirty(quote! {Self::__relock(__sources, __location).await}),
+                quote! {Self::__relock(sources, location).await},
+            )
+        } else {
+            (
+                quote! {
+                    fn __relock(__sources: #sources_ident<'a>, __location: #location) -> #output {
+                        let (#(#sources,)*) = __sources;
+                        #setup
+                        #body
+                    }
+                },
+                Some(restore_dirty(quote! {Self::__relock(__sources, __location)})),
+                restore_dirty(quote! {Self::__relock(__sources, __location)}),
+                quote! {Self::__relock(sources, location)},
+            )
+        };
+
+        let (result_left, result_right, with_result) = if state.is_fallible() {
+            (
+                quote! {Result<},
+                quote! {, Box<dyn std::error::Error + 'a>>},
+                quote! {.map(|state| (state, result))},
+            )
+        } else {
+            (quote! {}, quote! {}, quote! {})
+        };
+        let wrap = |relocked: TokenStream| {
+            if state.is_fallible() {
+                quote! {#relocked #with_result}
+            } else {
+                quote! {(#relocked, result)}
+            }
+        };
+        let nested_result = if state.is_fallible() {
+            awaited.clone()
+        } else {
+            quote! {Ok(#awaited)}
+        };
+
+        let reacquire = |relocked: &TokenStream| {
+            if state.is_fallible() {
+                quote! {
+                    match #relocked {
+                        Ok(state) => state,
+                        Err(error) => return Err(error),
+                    }
+                }
+            } else {
+                relocked.to_owned()
+            }
+        };
+        let done = |state_ident: TokenStream| {
+            if state.is_fallible() {
+                quote! {Ok(#state_ident)}
+            } else {
+                state_ident
+            }
+        };
+
+        let wait_until_async = if cfg!(feature = "tokio") {
+            let reacquired = reacquire(&relocked_async);
+            let done = done(quote! {state});
+            quote! {
+                /// Waits until `predicate` holds for the state, releasing the locks while waiting for
+                /// a notification of `notify` and reacquiring them in lock order to check again
+                #[track_caller]
+                pub fn wait_until_async<P>(self, notify: &'a ::deadlocker::condvar::Notify, mut predicate: P) -> impl std::future::Future<Output = #result_left Self #result_right> + 'a
+                where
+                    P: FnMut(&mut Self) -> bool + 'a,
+                {
+                    let __location = std::panic::Location::caller();
+                    async move {
+                        let mut state = self;
+                        while !predicate(&mut state) {
+                            let mut notified = Box::pin(notify.notified());
+                            notified.as_mut().enable();
+                            let (__sources, __dirty) = state.__release();
+                            notified.await;
+                            state = #reacquired;
+                        }
+                        #done
+                    }
+                }
+            }
+        } else {
+            quote! {}
+        };
+
+        let unlocked = match relocked {
+            Some(relocked) => {
+                let reacquired = reacquire(&relocked);
+                let done = done(quote! {self});
+                let relocked = wrap(relocked);
+                quote! {
+                    /// Releases the locks while running `f`, reacquiring them in lock order afterwards
+                    ///
+                    /// Useful for calling code which may need the same locks, such as callbacks
+                    #[track_caller]
+                    pub fn unlocked<R>(self, f: impl FnOnce() -> R) -> #result_left (Self, R) #result_right {
+                        let __location = std::panic::Location::caller();
+                        let (__sources, __dirty) = self.__release();
+                        let result = f();
+                        #relocked
+                    }
+
+                    /// Waits until `predicate` holds for the state, releasing the locks while waiting for
+                    /// a notification of `condvar` and reacquiring them in lock order to check again
+                    #[track_caller]
+                    pub fn wait_until(mut self, condvar: &::deadlocker::Condvar, mut predicate: impl FnMut(&mut Self) -> bool) -> #result_left Self #result_right {
+                        let __location = std::panic::Location::caller();
+                        while !predicate(&mut self) {
+                            let generation = condvar.__generation();
+                            let (__sources, __dirty) = self.__release();
+                            condvar.__wait(generation);
+                            self = #reacquired;
+                        }
+                        #done
+                    }
+
+                    #[doc(hidden)]
+                    pub fn __relock_nested_sync(sources: #sources_ident<'a>, location: #location) -> Result<Self, Box<dyn std::error::Error + 'a>> {
+                        #nested_result
+                    }
+                }
+            }
+            None => quote! {},
+        };
+        let relocked_async = wrap(relocked_async);
+
+        quote! {
+            impl<'a> #state_ident<'a> {
+                #unlocked
+
+                /// Releases the locks while awaiting the future returned by `f`, reacquiring them in
+                /// lock order afterwards
+                #[track_caller]
+                pub fn unlocked_async<F, Fut>(self, f: F) -> impl std::future::Future<Output = #result_left (Self, Fut::Output) #result_right> + 'a
+                where
+                    F: FnOnce() -> Fut + 'a,
+                    Fut: std::future::Future + 'a,
+                {
+                    let __location = std::panic::Location::caller();
+                    async move {
+                        let (__sources, __dirty) = self.__release();
+                        let result = f().await;
+                        #relocked_async
+                    }
+                }
+
+                #wait_until_async
+
+                #[doc(hidden)]
+                pub async fn __relock_nested(sources: #sources_ident<'a>, location: #location) -> Result<Self, Box<dyn std::error::Error + 'a>> {
+                    #nested_result
+                }
+
+                /// Releases the locks, returning what is needed to reacquire them along with which
+                /// fields were mutably accessed
+                fn __release(self) -> (#sources_ident<'a>, #dirty_ident) {
+                    let dirty = self.__dirty();
+                    (self._sources, dirty)
+                }
+
+                #relock
+            }
+        }
+        .to_tokens(&mut implementations);
+    }
+
+    implementations
+}
+
+/// Generate the token stream for the implementations of `Transactional` for the states, along with
+/// the `lock_transaction` methods of every builder for structs marked with `transaction`
+///
+/// The states only implement `Transactional` if all locked values implement `Clone`, which is
+/// deferred to the use of the implementation through higher-ranked bounds. The implementations are
+/// generated regardless of `transaction`, so that structs flattening this one may opt in.
+pub fn generate_transaction_implementations(
+    locker_struct_name: &Ident,
+    struct_attrs: &[syn::Attribute],
+    states: &[State],
+) -> TokenStream {
+    let mut implementations = TokenStream::new();
+    let opted_in = struct_attrs.locker_arg(TRANSACTION).is_some();
+    let transactional = quote! {::deadlocker::transaction::Transactional};
+
+    for state in states.iter().filter(|state| !state.active.is_empty()) {
+        let state_ident = state.ident();
+        let mut bounds = Vec::new();
+        let mut types = Vec::new();
+        let mut snapshots = Vec::new();
+        let mut restores = Vec::new();
+        let mut originals = Vec::new();
+
+        for group in state.groups() {
+            let ident = &group.field.ident;
+            let original = format_ident!(
+                "__original_{}",
+                ident.as_ref().expect("Fields must be named")
+            );
+            if group.field.flattened().is_some() {
+                let suffix = group
+                    .nested
+                    .iter()
+                    .map(|nested| pascal_case(&nested.to_string()))
+                    .collect::<String>();
+                let nested_state = group.field.nested_path(&format!("Locker{}", suffix));
+                bounds.push(quote! {for<'__t> #nested_state<'a>: #transactional});
+                types.push(quote! {<#nested_state<'a> as #transactional>::Snapshot});
+                snapshots.push(quote! {#transactional::snapshot(&self.#ident)});
+                restores.push(quote! {#transactional::restore(&mut self.#ident, #original);});
+            } else if group.field.selection_type().is_some() {
+                let return_type = group.field.return_type();
+                bounds.push(quote! {for<'__t> #return_type: Clone});
+                types.push(quote! {Vec<#return_type>});
+                snapshots.push(quote! {
+                    self.#ident.iter().map(|(_, value)| Clone::clone(value)).collect()
+                });
+                restores.push(quote! {
+                    if self.#ident.is_dirty() {
+                        for ((_, value), original) in self.#ident.iter_mut().zip(#original) {
+                            *value = original;
+                        }
+                    }
+                });
+            } else {
+                let return_type = group.field.return_type();
+                bounds.push(quote! {for<'__t> #return_type: Clone});
+                types.push(quote! {#return_type});
+                snapshots.push(quote! {Clone::clone(&**self.#ident)});
+                restores.push(quote! {
+                    if ::deadlocker::guard::Dirty::is_dirty(&*self.#ident) {
+                        **self.#ident = #original;
+                    }
+                });
+            }
+            originals.push(original);
+        }
+
+        let transaction = quote! {::deadlocker::Transaction<#state_ident<'a>>};
+        let (output, started) = match state.levels() {
+            Some((_, max)) => {
+                let max = format_ident!("L{}", max);
+                let token = quote! {::deadlocker::LockToken<'a, ::deadlocker::level::#max>};
+                if state.is_fallible() {
+                    (
+                        quote! {Result<(#transaction, #token), Box<dyn std::error::Error + 'a>>},
+                        quote! {locked.map(|(state, token)| (::deadlocker::Transaction::new(state), token))},
+                    )
+                } else {
+                    (
+                        quote! {(#transaction, #token)},
+                        quote! {(::deadlocker::Transaction::new(locked.0), locked.1)},
+                    )
+                }
+            }
+            None if state.is_fallible() => (
+                quote! {Result<#transaction, Box<dyn std::error::Error + 'a>>},
+                quote! {locked.map(::deadlocker::Transaction::new)},
+            ),
+            None => (quote! {#transaction}, quote! {::deadlocker::Transaction::new(locked)}),
+        };
+        let lock_transaction = if opted_in {
+            let method = generate_terminal_method(
+                state,
+                &format_ident!("lock_transaction"),
+                output,
+                quote! {#state_ident<'a>: #transactional},
+                started,
+            );
+            quote! {
+                impl<'a> #locker_struct_name<'a, #state_ident<'a>> {
+                    /// Acquires the locks like `lock`, restoring the locked values when the returned
+                    /// transaction is dropped without being committed
+                    #method
+                }
+            }
+        } else {
+            quote! {}
+        };
+
+        quote! {
+            impl<'a> #transactional for #state_ident<'a>
+            where
+                #(#bounds,)*
+            {
+                type Snapshot = (#(#types,)*);
+
+                fn snapshot(&self) -> Self::Snapshot {
+                    (#(#snapshots,)*)
+                }
+
+                fn restore(&mut self, snapshot: Self::Snapshot) {
+                    let (#(#originals,)*) = snapshot;
+                    #(#restores)*
+                }
+            }
+
+            #lock_transaction
+        }
+        .to_tokens(&mut implementations);
+    }
+
+    implementations
+}
+
+/// Generate the token stream for the `{Struct}Snapshot{Fields}` structs holding clones of the
+/// locked values, along with the `snapshot` methods of every builder for structs marked with
+/// `snapshot`
+///
+/// As with transactions, the conversions from the states only apply if all locked values implement
+/// `Clone`, and are generated regardless of `snapshot`, so that structs flattening this one may opt
+/// in.
+pub fn generate_snapshot_implementations(
+    locker_struct_name: &Ident,
+    struct_attrs: &[syn::Attribute],
+    states: &[State],
+) -> TokenStream {
+    let mut implementations = TokenStream::new();
+    let opted_in = struct_attrs.locker_arg(SNAPSHOT).is_some();
+
+    for state in states.iter().filter(|state| !state.active.is_empty()) {
+        let state_ident = state.ident();
+        let snapshot_ident = format_ident!("{}Snapshot{}", state.struct_ident, state.to_string());
+        let mut bounds = Vec::new();
+        let mut fields = Vec::new();
+        let mut clones = Vec::new();
+
+        for group in state.groups() {
+            let ident = &group.field.ident;
+
+            if group.field.flattened().is_some() {
+                let suffix = group
+                    .nested
+                    .iter()
+                    .map(|nested| pascal_case(&nested.to_string()))
+                    .collect::<String>();
+                let nested_state = group.field.nested_path(&format!("Locker{}", suffix));
+                let nested_snapshot = group.field.nested_path(&format!("Snapshot{}", suffix));
+                bounds.push(quote! {for<'__s> #nested_snapshot: From<&'__s #nested_state<'a>>});
+                fields.push(quote! {pub #ident: #nested_snapshot});
+                clones.push(quote! {#ident: From::from(&state.#ident)});
+            } else if let Some(selection_type) = group.field.selection_type() {
+                let return_type = group.field.return_type();
+                bounds.push(quote! {for<'__s> #return_type: Clone});
+                bounds.push(quote! {for<'__s> #selection_type: Clone});
+                fields.push(
+                    quote! {pub #ident: std::collections::BTreeMap<#selection_type, #return_type>},
+                );
+                clones.push(quote! {
+                    #ident: state
+                        .#ident
+                        .iter()
+                        .map(|(key, value)| (Clone::clone(key), Clone::clone(value)))
+                        .collect()
+                });
+            } else {
+                let return_type = group.field.return_type();
+                bounds.push(quote! {for<'__s> #return_type: Clone});
+                fields.push(quote! {pub #ident: #return_type});
+                clones.push(quote! {#ident: Clone::clone(&**state.#ident)});
+            }
+        }
+
+        let output = if state.is_fallible() {
+            quote! {Result<#snapshot_ident, Box<dyn std::error::Error + 'a>>}
+        } else {
+            quote! {#snapshot_ident}
+        };
+        let finish = match (state.levels().is_some(), state.is_fallible()) {
+            (false, false) => quote! {#snapshot_ident::from(&locked)},
+            (false, true) => quote! {locked.map(|state| #snapshot_ident::from(&state))},
+            (true, false) => quote! {#snapshot_ident::from(&locked.0)},
+            (true, true) => quote! {locked.map(|(state, _)| #snapshot_ident::from(&state))},
+        };
+        let snapshot = if opted_in {
+            let method = generate_terminal_method(
+                state,
+                &format_ident!("snapshot"),
+                output,
+                quote! {for<'__s> #snapshot_ident: From<&'__s #state_ident<'a>>},
+                finish,
+            );
+            quote! {
+                impl<'a> #locker_struct_name<'a, #state_ident<'a>> {
+                    /// Acquires the locks like `lock`, returning clones of the locked values once
+                    /// the locks have been released again
+                    #method
+                }
+            }
+        } else {
+            quote! {}
+        };
+        let doc = format!(
+            "Clones of the values locked by [{}], taken through `snapshot`",
+            state_ident
+        );
+
+        quote! {
+            #[doc = #doc]
+            pub struct #snapshot_ident {
+                #(#fields,)*
+            }
+
+            impl<'a> From<&#state_ident<'a>> for #snapshot_ident
+            where
+                #(#bounds,)*
+            {
+                fn from(state: &#state_ident<'a>) -> Self {
+                    #snapshot_ident {
+                        #(#clones,)*
+                    }
+                }
+            }
+
+            #snapshot
+        }
+        .to_tokens(&mut implementations);
+    }
+
+    implementations
+}
+
+/// Generate the token stream for a method of a builder acquiring the locks through `lock` and
+/// turning the result bound to `locked` into the output through `finish`
+///
+/// Like `lock`, the method takes a token for structs with levels, and returns a future for
+/// asynchronous states
+fn generate_terminal_method(
+    state: &State,
+    ident: &Ident,
+    output: TokenStream,
+    bound: TokenStream,
+    finish: TokenStream,
+) -> TokenStream {
+    let (generics, arguments, bounds, lock_call) = match state.levels() {
+        Some((min, max)) => {
+            let min = format_ident!("L{}", min);
+            let max = format_ident!("L{}", max);
+            (
+                quote! {<L>},
+                quote! {, token: &'a mut ::deadlocker::LockToken<'_, L>},
+                quote! {
+                    L: ::deadlocker::level::Level,
+                    ::deadlocker::level::#min: ::deadlocker::level::LockAfter<L>,
+                    ::deadlocker::level::#max: ::deadlocker::level::LockAfter<L>,
+                },
+                quote! {self.lock(token)},
+            )
+        }
+        None => (quote! {}, quote! {}, quote! {}, quote! {self.lock()}),
+    };
+
+    if state.is_async() {
+        quote! {
+            #[track_caller]
+            pub fn #ident #generics(self #arguments) -> impl std::future::Future<Output = #output> + 'a
+            where
+                #bound,
+                #bounds
+            {
+                let locking = #lock_call;
+                async move {
+                    let locked = locking.await;
+                    #finish
+                }
+            }
+        }
+    } else {
+        quote! {
+            #[track_caller]
+            pub fn #ident #generics(self #arguments) -> #output
+            where
+                #bound,
+                #bounds
+            {
+                let locked = #lock_call;
+                #finish
+            }
+        }
+    }
+}
+
+/// Generate the token stream for the conversions of every state into the states holding a subset
+/// of its locks, releasing the other locks
+pub fn generate_narrow_implementations(states: &[State]) -> TokenStream {
+    let mut conversions = TokenStream::new();
+
+    for from in states {
+        for to in states.iter().filter(|to| {
+            !to.active.is_empty()
+                && to.active.len() < from.active.len()
+                && to.active.iter().all(|lockable| from.active.contains(lockable))
+        }) {
+            let from_ident = from.ident();
+            let to_ident = to.ident();
+            let from_groups = from.groups();
+            let (fields, sources): (Vec<TokenStream>, Vec<TokenStream>) = to
+                .groups()
+                .into_iter()
+                .map(|group| {
+                    let ident = &group.field.ident;
+                    if group.field.flattened().is_some() {
+                        // The nested state is narrowed as well, changing its sources
+                        let suffix = group
+                            .nested
+                            .iter()
+                            .map(|nested| pascal_case(&nested.to_string()))
+                            .collect::<String>();
+                        let nested_state = group.field.nested_path(&format!("Locker{}", suffix));
+                        (
+                            quote! {let #ident: #nested_state<'a> = from.#ident.into();},
+                            quote! {#ident.__sources()},
+                        )
+                    } else {
+                        let index = from_groups
+                            .iter()
+                            .position(|from_group| from_group.field == group.field)
+                            .expect("Expected the fields of a subset state to be locked");
+                        let index = syn::Index::from(index);
+                        (
+                            quote! {let #ident = from.#ident;},
+                            quote! {from._sources.#index},
+                        )
+                    }
+                })
+                .unzip();
+            let idents = to.groups().into_iter().map(|group| &group.field.ident);
+
+            quote! {
+                impl<'a> From<#from_ident<'a>> for #to_ident<'a> {
+                    fn from(from: #from_ident<'a>) -> Self {
+                        #(#fields)*
+                        #to_ident {
+                            _sources: (#(#sources,)*),
+                            #(#idents,)*
+                            _acquired_at: from._acquired_at,
+                        }
+                    }
+                }
+            }
+            .to_tokens(&mut conversions);
+        }
+    }
+
+    conversions
+}
+
+/// Generate the token stream for the `LOCK_FIELDS` constant and the `LockerMeta` trait
+///
+/// Also checks at compile time that the fields listed in `flatten` follow the lock order of the
+/// nested struct, as the names of its states depend on it
+pub fn generate_meta_implementation(
+    struct_identifier: &Ident,
+    all_ordered: &[Lockable],
+) -> TokenStream {
+    let struct_name = struct_identifier.to_string();
+    let fields = ordered_fields(all_ordered)
+        .into_iter()
+        .enumerate()
+        .map(|(rank, field)| {
+            let field_name = field
+                .ident
+                .as_ref()
+                .expect("Fields must be named")
+                .to_string();
+            let (kind, inner_type) = if field.flattened().is_some() {
+                (quote! {Flattened}, type_name(&field.ty))
+            } else {
+                let kind = if field.is_keyed() {
+                    quote! {Keyed}
+                } else if field.is_collection() {
+                    quote! {Collection}
+                } else {
+                    quote! {Single}
+                };
+                (kind, type_name(&field.return_type()))
+            };
+            let is_async = field.is_async();
+            let is_result = field.is_result();
+
+            quote! {
+                ::deadlocker::LockFieldInfo {
+                    module_path: module_path!(),
+                    struct_name: #struct_name,
+                    field_name: #field_name,
+                    rank: #rank,
+                    inner_type: #inner_type,
+                    is_async: #is_async,
+                    is_result: #is_result,
+                    kind: ::deadlocker::LockKind::#kind,
+                }
+            }
+        });
+
+    let flatten_checks = ordered_fields(all_ordered).into_iter().filter_map(|field| {
+        let names = field.flattened()?.iter().map(Ident::to_string).collect::<Vec<String>>();
+        let ty = &field.ty;
+        let message = format!(
+            "The fields listed in `flatten({})` of `{}` must be fields of `{}`, listed in the order \
+            they are declared in",
+            names.join(", "),
+            field.ident.as_ref().expect("Fields must be named"),
+            type_name(ty)
+        );
+        Some(quote! {
+            const _: () = assert!(
+                ::deadlocker::LockFieldInfo::__in_lock_order(<#ty>::LOCK_FIELDS, &[#(#names),*]),
+                #message
+            );
+        })
+    });
+
+    quote! {
+        impl #struct_identifier {
+            /// The locked fields of the struct, in lock order
+            pub const LOCK_FIELDS: &'static [::deadlocker::LockFieldInfo] = &[#(#fields),*];
+        }
+
+        #(#flatten_checks)*
+
+        impl ::deadlocker::LockerMeta for #struct_identifier {
+            const LOCK_FIELDS: &'static [::deadlocker::LockFieldInfo] = #struct_identifier::LOCK_FIELDS;
+        }
+    }
+}
+
+/// Formats a type as written in source, such as `Vec<usize>`
+fn type_name(ty: &impl ToTokens) -> String {
+    ty.to_token_stream()
+        .to_string()
+        .replace(" <", "<")
+        .replace("< ", "<")
+        .replace(" >", ">")
+        .replace(" ::", "::")
+        .replace(":: ", "::")
+        .replace(" ,", ",")
+        .replace("& ", "&")
+}
+
+/// Generate the token stream for the `lock_stats` function of structs marked with `stats`
+pub fn generate_stats_implementation(
+    struct_identifier: &Ident,
+    struct_attrs: &[syn::Attribute],
+    all_ordered: &[Lockable],
+) -> TokenStream {
+    let fields = match stats_fields(struct_attrs, all_ordered) {
+        Some(fields) => fields,
+        None => return quote! {},
+    };
+    let len = fields.len();
+    let stats = fields.iter().map(|field| {
+        let info = field_info(struct_identifier, all_ordered, field);
+        quote! {::deadlocker::stats::FieldStats::new(#info)}
+    });
+
+    quote! {
+        impl #struct_identifier {
+            /// Returns the lock statistics of each field, in lock order
+            pub fn lock_stats() -> &'static [::deadlocker::stats::FieldStats] {
+                static STATS: [::deadlocker::stats::FieldStats; #len] = [#(#stats),*];
+                &STATS
+            }
+        }
+    }
+}
+
+/// Generate the token stream for the `subscribe` methods of fields marked with `watch`
+pub fn generate_watch_implementation(
+    struct_identifier: &Ident,
+    all_ordered: &[Lockable],
+) -> TokenStream {
+    let methods = ordered_fields(all_ordered)
+        .into_iter()
+        .filter(|field| field.is_watched())
+        .map(|field| {
+            if !field.is_shared() {
+                panic!("Only fields shared through an Arc or Rc can be watched");
+            }
+            let ident = field.ident.as_ref().expect("Fields must be named");
+            let ty = &field.ty;
+            let subscribe = format_ident!("subscribe_{}", ident);
+            let doc = format!(
+                "Subscribes to mutations of `{}`, receiving the new version whenever a guard which \
+                was mutably dereferenced is released",
+                ident
+            );
+            let subscribe_async = if cfg!(feature = "tokio") {
+                let subscribe_async = format_ident!("subscribe_{}_async", ident);
+                let doc = format!(
+                    "Subscribes to mutations of `{}` through a `tokio::sync::watch` channel holding \
+                    the latest version",
+                    ident
+                );
+                quote! {
+                    #[doc = #doc]
+                    pub fn #subscribe_async(&self) -> ::deadlocker::watch::AsyncReceiver<#ty> {
+                        ::deadlocker::watch::subscribe_async(&self.#ident)
+                    }
+                }
+            } else {
+                quote! {}
+            };
+
+            quote! {
+                #[doc = #doc]
+                pub fn #subscribe(&self) -> ::deadlocker::watch::Receiver<#ty> {
+                    ::deadlocker::watch::subscribe(&self.#ident)
+                }
+
+                #subscribe_async
+            }
+        })
+        .collect::<Vec<TokenStream>>();
+
+    if methods.is_empty() {
+        return quote! {};
+    }
+
+    quote! {
+        impl #struct_identifier {
+            #(#methods)*
+        }
+    }
+}
+
+/// Generate the token stream for the `Serialize` and `Deserialize` implementations of structs
+/// marked with `serde`
+///
+/// Serializing acquires all locked fields in lock order, holding the guards until the values have
+/// been serialized. Structs containing asynchronous locks are rejected, as the locks can not be
+/// awaited.
+pub fn generate_serde_implementation(
+    struct_identifier: &Ident,
+    struct_attrs: &[syn::Attribute],
+    fields: &syn::Fields,
+    all_ordered: &[Lockable],
+) -> TokenStream {
+    if struct_attrs.locker_arg(SERDE).is_none() {
+        return quote! {};
+    }
+
+    let ordered = ordered_fields(all_ordered);
+    if let Some(field) = ordered.iter().find(|field| field.is_async()) {
+        return syn::Error::new_spanned(
+            field,
+            "Structs containing asynchronous locks can not be marked with `serde`, as serializing \
+            them would have to await the locks",
+        )
+        .to_compile_error();
+    }
+
+    let serde = quote! {::deadlocker::serde_support::serde};
+    let mirror_ident = format_ident!("{}SerdeFields", struct_identifier);
+    let name = struct_identifier.to_string();
+    let idents = fields
+        .iter()
+        .map(|field| field.ident.as_ref().expect("Fields must be named"))
+        .collect::<Vec<&Ident>>();
+    let types = fields.iter().map(|field| &field.ty).collect::<Vec<&syn::Type>>();
+    let bound = types
+        .iter()
+        .map(|ty| format!("for<'__d> {}: {}::Deserialize<'de>", ty.to_token_stream(), serde))
+        .collect::<Vec<String>>()
+        .join(", ");
+    let serde_crate = serde.to_string().replace(' ', "");
+
+    let deserialize = quote! {
+        #[doc(hidden)]
+        #[derive(#serde::Deserialize)]
+        #[serde(crate = #serde_crate, rename = #name, bound(deserialize = #bound))]
+        pub struct #mirror_ident {
+            #(pub #idents: #types,)*
+        }
+
+        impl<'de> #serde::Deserialize<'de> for #struct_identifier
+        where
+            #mirror_ident: #serde::Deserialize<'de>,
+        {
+            fn deserialize<D: #serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let fields = <#mirror_ident as #serde::Deserialize>::deserialize(deserializer)?;
+                Ok(#struct_identifier {
+                    #(#idents: fields.#idents,)*
+                })
+            }
+        }
+    };
+
+
+    let guards_ident = format_ident!("{}SerdeGuards", struct_identifier);
+    let view_ident = format_ident!("{}SerdeView", struct_identifier);
+    let mut guard_types = Vec::new();
+    let mut locks = Vec::new();
+
+    for field in &ordered {
+        let ident = &field.ident;
+        let guard = |element: TokenStream| {
+            let lock_method = field.lock_method();
+            let return_type = field.return_type();
+            let locked = if field.is_result() {
+                quote! {#element.#lock_method.map_err(|error| error.to_string())?}
+            } else {
+                quote! {#element.#lock_method}
+            };
+            quote! {Box::new(#locked) as Box<dyn std::ops::Deref<Target = #return_type> + '_>}
+        };
+
+        if field.flattened().is_some() {
+            let nested_guards = field.nested_path("SerdeGuards");
+            guard_types.push(quote! {#nested_guards<'g>});
+            locks.push(quote! {self.#ident.__serde_lock()?});
+        } else if field.is_keyed() {
+            let return_type = field.return_type();
+            let selection_type = field.selection_type();
+            let guard = guard(quote! {element});
+            guard_types.push(quote! {
+                Vec<(&'g #selection_type, Box<dyn std::ops::Deref<Target = #return_type> + 'g>)>
+            });
+            locks.push(quote! {{
+                let mut entries = self.#ident.iter().collect::<Vec<_>>();
+                entries.sort_by(|a, b| a.0.cmp(b.0));
+                entries
+                    .into_iter()
+                    .map(|(key, element)| Ok((key, #guard)))
+                    .collect::<Result<Vec<_>, String>>()?
+            }});
+        } else if field.is_collection() {
+            let return_type = field.return_type();
+            let guard = guard(quote! {element});
+            guard_types.push(quote! {Vec<Box<dyn std::ops::Deref<Target = #return_type> + 'g>>});
+            locks.push(quote! {
+                self.#ident
+                    .iter()
+                    .map(|element| Ok(#guard))
+                    .collect::<Result<Vec<_>, String>>()?
+            });
+        } else {
+            let return_type = field.return_type();
+            guard_types.push(quote! {Box<dyn std::ops::Deref<Target = #return_type> + 'g>});
+            locks.push(guard(quote! {self.#ident}));
+        }
+    }
+
+    let mut bounds = Vec::new();
+    let mut serialized = Vec::new();
+
+    for field in fields {
+        let ident = field.ident.as_ref().expect("Fields must be named");
+        let name = ident.to_string();
+        let value = match ordered.iter().position(|ordered| *ordered == field) {
+            Some(index) => {
+                let index = syn::Index::from(index);
+                if field.flattened().is_some() {
+                    let nested_view = field.nested_path("SerdeView");
+                    bounds.push(quote! {for<'__v, '__g> #nested_view<'__v, '__g>: #serde::Serialize});
+                    quote! {
+                        &#nested_view {
+                            value: &self.value.#ident,
+                            guards: &self.guards.#index,
+                        }
+                    }
+                } else if field.is_keyed() {
+                    let return_type = field.return_type();
+                    let selection_type = field.selection_type();
+                    bounds.push(quote! {for<'__s> #return_type: #serde::Serialize});
+                    bounds.push(quote! {for<'__s> #selection_type: #serde::Serialize});
+                    quote! {&::deadlocker::serde_support::EntryGuards(&self.guards.#index)}
+                } else if field.is_collection() {
+                    let return_type = field.return_type();
+                    bounds.push(quote! {for<'__s> #return_type: #serde::Serialize});
+                    quote! {&::deadlocker::serde_support::ElementGuards(&self.guards.#index)}
+                } else {
+                    let return_type = field.return_type();
+                    bounds.push(quote! {for<'__s> #return_type: #serde::Serialize});
+                    quote! {&**self.guards.#index}
+                }
+            }
+            None => {
+                let ty = &field.ty;
+                bounds.push(quote! {for<'__s> #ty: #serde::Serialize});
+                quote! {&self.value.#ident}
+            }
+        };
+        serialized.push(quote! {
+            #serde::ser::SerializeStruct::serialize_field(&mut serialized, #name, #value)?;
+        });
+    }
+
+    let len = fields.len();
+
+    quote! {
+        #deserialize
+
+        #[doc(hidden)]
+        pub type #guards_ident<'g> = (#(#guard_types,)*);
+
+        #[doc(hidden)]
+        pub struct #view_ident<'v, 'g> {
+            pub value: &'v #struct_identifier,
+            pub guards: &'v #guards_ident<'g>,
+        }
+
+        impl #struct_identifier {
+            #[doc(hidden)]
+            pub fn __serde_lock(&self) -> Result<#guards_ident<'_>, String> {
+                Ok((#(#locks,)*))
+            }
+        }
+
+        impl<'v, 'g> #serde::Serialize for #view_ident<'v, 'g>
+        where
+            #(#bounds,)*
+        {
+            fn serialize<S: #serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                let mut serialized = serializer.serialize_struct(#name, #len)?;
+                #(#serialized)*
+                #serde::ser::SerializeStruct::end(serialized)
+            }
+        }
+
+        impl #serde::Serialize for #struct_identifier
+        where
+            for<'__v, '__g> #view_ident<'__v, '__g>: #serde::Serialize,
+        {
+            fn serialize<S: #serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                let guards = self
+                    .__serde_lock()
+                    .map_err(<S::Error as #serde::ser::Error>::custom)?;
+                let view = #view_ident {
+                    value: self,
+                    guards: &guards,
+                };
+                #serde::Serialize::serialize(&view, serializer)
+            }
+        }
+    }
+}
+
+/// Generate the token stream for the `Debug` implementation of structs marked with `debug`
+///
+/// The locked fields are attempted in lock order without blocking, and formatted in declaration
+/// order. Flattened fields are formatted through the `Debug` implementation of the nested struct.
+pub fn generate_debug_implementation(
+    struct_identifier: &Ident,
+    struct_attrs: &[syn::Attribute],
+    fields: &syn::Fields,
+    all_ordered: &[Lockable],
+) -> TokenStream {
+    if struct_attrs.locker_arg(DEBUG).is_none() {
+        return quote! {};
+    }
+
+    let ordered = ordered_fields(all_ordered)
+        .into_iter()
+        .filter(|field| field.flattened().is_none())
+        .collect::<Vec<&Field>>();
+    let attempts = ordered.iter().map(|field| {
+        let ident = &field.ident;
+        let try_lock_method = field.try_lock_method();
+        let attempt = |element: TokenStream| {
+            if field.is_async() {
+                quote! {
+                    ::deadlocker::stats::TryAcquire::attempt(
+                        ::deadlocker::stats::Unpoisoned(#element.#try_lock_method)
+                    )
+                }
+            } else {
+                quote! {::deadlocker::stats::TryAcquire::attempt(#element.#try_lock_method)}
+            }
+        };
+
+        if field.is_keyed() {
+            let attempt = attempt(quote! {element});
+            quote! {
+                let #ident = self.#ident
+                    .iter()
+                    .map(|(key, element)| (key, #attempt))
+                    .collect::<std::collections::BTreeMap<_, _>>();
+            }
+        } else if field.is_collection() {
+            let attempt = attempt(quote! {element});
+            quote! {
+                let #ident = self.#ident
+                    .iter()
+                    .map(|element| #attempt)
+                    .collect::<Vec<_>>();
+            }
+        } else {
+            let attempt = attempt(quote! {self.#ident});
+            quote! {let #ident = #attempt;}
+        }
+    });
+    let formatted = fields.iter().map(|field| {
+        let ident = field.ident.as_ref().expect("Fields must be named");
+        let name = ident.to_string();
+        if ordered.contains(&field) {
+            quote! {.field(#name, &#ident)}
+        } else {
+            quote! {.field(#name, &self.#ident)}
+        }
+    });
+    let name = struct_identifier.to_string();
+
+    quote! {
+        impl std::fmt::Debug for #struct_identifier {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                #(#attempts)*
+                f.debug_struct(#name)
+                    #(#formatted)*
+                    .finish()
             }
         }
     }
@@ -148,29 +1560,140 @@ pub fn generate_state_struct_declarations(states: &[State]) -> TokenStream {
 
     for state in states {
         let state_name = state.ident();
-        let state_fields_ident = state.active.iter().map(|f| {
-            f.ident
-                .as_ref()
-                .expect("All fields must be named")
-                .to_owned()
-        });
-
-        let state_fields_return_type = state.active.iter().map(Field::return_type);
         let deref_trait = if true {
             quote! {std::ops::DerefMut}
         } else {
             quote! {std::ops::Deref}
         };
 
-        let parameters = if !state.active.is_empty() {
-            quote! {<'a>}
-        } else {
-            quote! {}
-        };
+        let state_fields = state.groups().into_iter().map(|group| {
+            let ident = &group.field.ident;
+            if group.field.flattened().is_some() {
+                let suffix = group
+                    .nested
+                    .iter()
+                    .map(|nested| pascal_case(&nested.to_string()))
+                    .collect::<String>();
+                let nested_state = group.field.nested_path(&format!("Locker{}", suffix));
+                quote! {pub #ident: #nested_state<'a>}
+            } else if let Some(selection_type) = group.field.selection_type() {
+                let return_type = group.field.return_type();
+                quote! {pub #ident: ::deadlocker::Elements<'a, #selection_type, #return_type>}
+            } else {
+                let return_type = group.field.return_type();
+                quote! {pub #ident: Box<::deadlocker::guard::Dirty<dyn #deref_trait<Target = #return_type> + 'a>>}
+            }
+        });
+
+        if state.active.is_empty() {
+            quote! {
+                pub struct #state_name {}
+            }
+            .to_tokens(&mut definitions);
+            continue;
+        }
+
+        let dirty_checks = state.groups().into_iter().map(|group| {
+            let ident = &group.field.ident;
+            let name = ident.as_ref().map(Ident::to_string);
+            let dirty = if group.field.flattened().is_some() {
+                quote! {!self.#ident.dirty_fields().is_empty()}
+            } else if group.field.selection_type().is_some() {
+                quote! {self.#ident.is_dirty()}
+            } else {
+                quote! {::deadlocker::guard::Dirty::is_dirty(&*self.#ident)}
+            };
+            quote! {
+                if #dirty {
+                    dirty.push(#name);
+                }
+            }
+        });
 
+        let (dirty_states, dirty_restores): (Vec<TokenStream>, Vec<TokenStream>) = state
+            .groups()
+            .into_iter()
+            .enumerate()
+            .map(|(index, group)| {
+                let ident = &group.field.ident;
+                let index = syn::Index::from(index);
+                if group.field.flattened().is_some() {
+                    (
+                        quote! {self.#ident.__dirty()},
+                        quote! {self.#ident.__restore_dirty(dirty.#index);},
+                    )
+                } else if group.field.selection_type().is_some() {
+                    (
+                        quote! {self.#ident.dirty_keys().cloned().collect()},
+                        quote! {self.#ident.mark_dirty(&dirty.#index);},
+                    )
+                } else {
+                    (
+                        quote! {::deadlocker::guard::Dirty::is_dirty(&*self.#ident)},
+                        quote! {
+                            if dirty.#index {
+                                ::deadlocker::guard::Dirty::mark_dirty(&mut *self.#ident);
+                            }
+                        },
+                    )
+                }
+            })
+            .unzip();
+
+        let sources_name = sources_ident(&state_name);
+        let sources_type = state.sources_type();
+        let dirty_name = dirty_ident(&state_name);
+        let dirty_type = state.dirty_type();
         quote! {
-            pub struct #state_name #parameters {
-                #(pub #state_fields_ident: Box<dyn #deref_trait<Target = #state_fields_return_type> + 'a>),*
+            #[doc(hidden)]
+            pub type #sources_name<'a> = #sources_type;
+
+            #[doc(hidden)]
+            pub type #dirty_name = #dirty_type;
+
+            pub struct #state_name<'a> {
+                #(#state_fields,)*
+                _acquired_at: &'static std::panic::Location<'static>,
+                _sources: #sources_name<'a>,
+            }
+
+            impl<'a> #state_name<'a> {
+                /// Returns the names of the fields which were mutably accessed, in lock order
+                ///
+                /// Flattened fields are included if any of their nested fields were
+                pub fn dirty_fields(&self) -> Vec<&'static str> {
+                    let mut dirty = Vec::new();
+                    #(#dirty_checks)*
+                    dirty
+                }
+
+                #[doc(hidden)]
+                pub fn __sources(&self) -> #sources_name<'a> {
+                    self._sources.clone()
+                }
+
+                #[doc(hidden)]
+                pub fn __dirty(&self) -> #dirty_name {
+                    (#(#dirty_states,)*)
+                }
+
+                #[doc(hidden)]
+                pub fn __restore_dirty(&mut self, dirty: #dirty_name) {
+                    #(#dirty_restores)*
+                }
+
+                /// Returns the location of the `lock` call which acquired the locks
+                pub fn acquired_at(&self) -> &'static std::panic::Location<'static> {
+                    self._acquired_at
+                }
+
+                /// Converts the state into a state holding a subset of its locks, releasing the others
+                pub fn narrow<T>(self) -> T
+                where
+                    Self: Into<T>,
+                {
+                    self.into()
+                }
             }
         }.to_tokens(&mut definitions)
     }
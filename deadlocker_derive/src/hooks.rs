@@ -0,0 +1,390 @@
+use proc_macro2::{Ident, TokenStream};
+use quote::quote;
+use syn::Field;
+
+use crate::{
+    attribute::AttributesAugment,
+    field::FieldAugment,
+    lockable::{ordered_fields, Lockable},
+    state::State,
+    OBSERVER, STATS,
+};
+
+/// The bookkeeping attached to the acquisition of each lock in a [State], depending on the enabled
+/// features
+pub struct Hooks {
+    struct_ident: Ident,
+    held: bool,
+    watchdog: bool,
+    tracing: bool,
+    stats: Option<Vec<Field>>,
+    observer: Option<TokenStream>,
+    contention: bool,
+    struct_attrs: Vec<syn::Attribute>,
+}
+
+impl Hooks {
+    pub fn for_state(state: &State) -> Hooks {
+        Hooks {
+            struct_ident: state.struct_ident.clone(),
+            held: cfg!(any(feature = "debug-order-check", feature = "lock-graph"))
+                && !state.is_async(),
+            watchdog: cfg!(feature = "watchdog"),
+            tracing: cfg!(feature = "tracing"),
+            stats: stats_fields(&state.struct_attrs, &state.all_ordered),
+            observer: local_observer(&state.struct_attrs),
+            contention: state.struct_attrs.locker_arg(STATS).is_some()
+                || state.struct_attrs.locker_arg(OBSERVER).is_some(),
+            struct_attrs: state.struct_attrs.clone(),
+        }
+    }
+
+    /// Returns the statements to run once before acquiring any of the locks of a state
+    pub fn setup(&self, state: &State) -> TokenStream {
+        let struct_name = state.struct_ident.to_string();
+        let held = if self.held {
+            quote! {
+                let __held_chain = ::deadlocker::held::ChainScope::enter();
+            }
+        } else {
+            quote! {}
+        };
+        let watchdog = if self.watchdog {
+            quote! {
+                let __watchdog = ::deadlocker::watchdog::begin(#struct_name, __location);
+            }
+        } else {
+            quote! {}
+        };
+        let tracing = if self.tracing {
+            let fields = field_names(state).join(", ");
+            quote! {
+                let __trace = ::deadlocker::trace::begin(#struct_name, #fields, __location);
+            }
+        } else {
+            quote! {}
+        };
+
+        let observer = match &self.observer {
+            Some(local) => {
+                let set_info = set_info(state);
+                quote! {
+                    let __observers = ::deadlocker::observer::Observers::new(#set_info, __location, #local);
+                }
+            }
+            None => quote! {},
+        };
+
+        quote! {
+            #held
+            #watchdog
+            #tracing
+            #observer
+        }
+    }
+
+    /// Returns the body of a synchronous `lock` method, or the future returned by an asynchronous
+    /// one, running the statements of [Hooks::setup] beforehand
+    pub fn instrument(&self, body: TokenStream, is_async: bool) -> TokenStream {
+        match (is_async, self.tracing) {
+            (false, false) => body,
+            (false, true) => quote! {
+                let __entered = __trace.enter();
+                #body
+            },
+            (true, false) => quote! {
+                async move {
+                    #body
+                }
+            },
+            (true, true) => quote! {
+                ::deadlocker::trace::LockSet::instrument(&__trace.clone(), async move {
+                    #body
+                })
+            },
+        }
+    }
+
+    /// Returns the statements to run right before acquiring a lock, binding the tokens later passed
+    /// to [Hooks::wrap]
+    pub fn before(&self, field: &Field, info: &TokenStream) -> TokenStream {
+        let held = if self.held {
+            quote! {
+                let held_token = ::deadlocker::held::acquire(#info, __location);
+            }
+        } else {
+            quote! {}
+        };
+        let watchdog = if self.watchdog {
+            quote! {
+                let watchdog_token = ::deadlocker::watchdog::wait(&__watchdog, #info);
+            }
+        } else {
+            quote! {}
+        };
+        let tracing = if self.tracing {
+            quote! {
+                let trace_token = ::deadlocker::trace::wait(&__trace, #info);
+            }
+        } else {
+            quote! {}
+        };
+        let stats = match &self.stats {
+            Some(fields) => {
+                let index = fields
+                    .iter()
+                    .position(|stats_field| stats_field == field)
+                    .expect("Expected statistics for every locked field");
+                let struct_ident = &self.struct_ident;
+                quote! {
+                    let mut stats_token = ::deadlocker::stats::FieldStats::begin(
+                        &#struct_ident::lock_stats()[#index]
+                    );
+                }
+            }
+            None => quote! {},
+        };
+        let observer = if self.observer.is_some() {
+            quote! {
+                let mut observer_token = __observers.before_acquire(#info);
+            }
+        } else {
+            quote! {}
+        };
+
+        quote! {
+            #held
+            #watchdog
+            #tracing
+            #stats
+            #observer
+        }
+    }
+
+    /// Returns the statements acquiring the lock of `element`, binding the resulting `guard`
+    ///
+    /// For structs collecting statistics or with an observer of their own, the lock is first
+    /// attempted without blocking, binding whether it was `contended`. Other structs are left
+    /// alone, as their locks may not offer a non-blocking method.
+    pub fn acquire(&self, field: &Field, info: &TokenStream) -> TokenStream {
+        let res = if field.is_result() {
+            self.check(info)
+        } else {
+            quote! {}
+        };
+        let lock_method = field.lock_method();
+
+        if !self.contention {
+            return quote! {
+                let guard = element.#lock_method #res;
+            };
+        }
+
+        let try_lock_method = field.try_lock_method();
+        let contended = if self.observer.is_some() {
+            quote! {observer_token.contended();}
+        } else {
+            quote! {}
+        };
+        let attempt = if field.is_async() {
+            quote! {::deadlocker::stats::Unpoisoned(element.#try_lock_method)}
+        } else {
+            quote! {element.#try_lock_method}
+        };
+        quote! {
+            let (guard, contended) =
+                match ::deadlocker::stats::TryAcquire::try_acquire(#attempt) {
+                    Some(guard) => (guard, false),
+                    None => {
+                        #contended
+                        (element.#lock_method #res, true)
+                    }
+                };
+        }
+    }
+
+    /// Returns the operator propagating the error of a failed acquisition of a field, notifying
+    /// any observers
+    pub fn check(&self, info: &TokenStream) -> TokenStream {
+        if self.observer.is_some() {
+            quote! {
+                .map_err(|error| {
+                    __observers.on_error(#info, &error);
+                    error
+                })?
+            }
+        } else {
+            quote! {?}
+        }
+    }
+
+    /// Returns the statements to run right after a lock has been acquired
+    pub fn after(&self, field: &Field, info: &TokenStream) -> TokenStream {
+        let watchdog = if self.watchdog {
+            quote! {watchdog_token.acquired();}
+        } else {
+            quote! {}
+        };
+        let tracing = if self.tracing {
+            quote! {trace_token.acquired();}
+        } else {
+            quote! {}
+        };
+        let stats = if self.stats.is_some() {
+            quote! {stats_token.acquired(contended);}
+        } else {
+            quote! {}
+        };
+        let observer = if self.observer.is_some() {
+            quote! {
+                observer_token.acquired();
+                let dirty_flag = observer_token.dirty_flag();
+            }
+        } else {
+            quote! {}
+        };
+        let hold = match field.warn_hold(&self.struct_attrs) {
+            Some(threshold) => quote! {
+                let hold_token = ::deadlocker::hold::watch(
+                    #info,
+                    std::time::Duration::from_nanos(#threshold),
+                    __location,
+                );
+            },
+            None => quote! {},
+        };
+
+        quote! {
+            #watchdog
+            #tracing
+            #stats
+            #observer
+            #hold
+        }
+    }
+
+    /// Returns the statements to run once all locks of a state have been acquired
+    pub fn finish(&self) -> TokenStream {
+        if self.tracing {
+            quote! {__trace.acquired();}
+        } else {
+            quote! {}
+        }
+    }
+
+    /// Returns the expression attaching the tokens bound by [Hooks::before] and [Hooks::after] to an
+    /// acquired guard, recording whether it is mutably dereferenced and watching it for mutations if
+    /// the field is watched
+    pub fn wrap(&self, field: &Field, guard: TokenStream) -> TokenStream {
+        let mut tokens = Vec::new();
+        if self.held {
+            tokens.push(quote! {held_token});
+        }
+        if self.watchdog {
+            tokens.push(quote! {watchdog_token});
+        }
+        if self.tracing {
+            tokens.push(quote! {trace_token});
+        }
+        if self.stats.is_some() {
+            tokens.push(quote! {stats_token});
+        }
+        if self.observer.is_some() {
+            tokens.push(quote! {observer_token});
+        }
+        if field.warn_hold(&self.struct_attrs).is_some() {
+            tokens.push(quote! {hold_token});
+        }
+
+        let guard = match tokens.len() {
+            0 => guard,
+            1 => quote! {::deadlocker::guard::Tracked::new(#guard, #(#tokens)*)},
+            _ => quote! {::deadlocker::guard::Tracked::new(#guard, (#(#tokens),*))},
+        };
+
+        // Subscribers are notified once the lock and the tokens have been released
+        let guard = if field.is_watched() {
+            quote! {::deadlocker::watch::Watched::new(#guard, ::deadlocker::watch::key(&**element))}
+        } else {
+            guard
+        };
+
+        if self.observer.is_some() {
+            quote! {::deadlocker::guard::Dirty::shared(#guard, dirty_flag)}
+        } else {
+            quote! {::deadlocker::guard::Dirty::new(#guard)}
+        }
+    }
+}
+
+/// Returns the fields to collect statistics for, in lock order, if enabled through
+/// `#[locker(stats)]`
+///
+/// Flattened fields are left out, as their statistics are collected by the nested struct
+pub fn stats_fields(
+    struct_attrs: &[syn::Attribute],
+    all_ordered: &[Lockable],
+) -> Option<Vec<Field>> {
+    struct_attrs.locker_arg(STATS)?;
+
+    let mut fields: Vec<Field> = Vec::new();
+    for lockable in all_ordered {
+        if lockable.nested.is_none() && !fields.contains(&lockable.field) {
+            fields.push(lockable.field.to_owned());
+        }
+    }
+    Some(fields)
+}
+
+/// Returns an expression for the observer set through `#[locker(observer = "...")]`, if observers
+/// are notified at all
+///
+/// With the `observer` feature enabled, observers are always notified, as a global observer may
+/// be installed at runtime
+fn local_observer(struct_attrs: &[syn::Attribute]) -> Option<TokenStream> {
+    match struct_attrs.locker_str(OBSERVER) {
+        Some(path) => {
+            let path = syn::parse_str::<syn::Path>(&path).expect("Expected a path to an observer");
+            Some(quote! {Some(&#path as &'static dyn ::deadlocker::LockObserver)})
+        }
+        None if cfg!(feature = "observer") => Some(quote! {None}),
+        None => None,
+    }
+}
+
+/// Returns the names of the fields locked by a [State], in lock order
+fn field_names(state: &State) -> Vec<String> {
+    state
+        .groups()
+        .iter()
+        .filter_map(|group| group.field.ident.as_ref().map(Ident::to_string))
+        .collect()
+}
+
+/// Returns an expression for the `&'static LockSetInfo` describing the fields of a [State]
+fn set_info(state: &State) -> TokenStream {
+    let struct_name = state.struct_ident.to_string();
+    let field_names = field_names(state);
+
+    quote! {
+        {
+            const SET: ::deadlocker::LockSetInfo = ::deadlocker::LockSetInfo {
+                module_path: module_path!(),
+                struct_name: #struct_name,
+                field_names: &[#(#field_names),*],
+            };
+            &SET
+        }
+    }
+}
+
+/// Returns an expression for the `&'static LockFieldInfo` describing a field, referring to the
+/// `LOCK_FIELDS` of the struct
+pub fn field_info(struct_ident: &Ident, all_ordered: &[Lockable], field: &Field) -> TokenStream {
+    let rank = ordered_fields(all_ordered)
+        .iter()
+        .position(|ordered| *ordered == field)
+        .expect("Expected every locked field to be ordered");
+
+    quote! {&#struct_ident::LOCK_FIELDS[#rank]}
+}
@@ -1,10 +1,17 @@
+use attribute::{AttributeAugment, AttributesAugment};
 use field::FieldAugment;
 use generators::{
-    generate_builder_struct, generate_impl_for_all_states, generate_state_struct_declarations,
-    generate_trait_implementation,
+    generate_builder_struct, generate_debug_implementation, generate_has_traits,
+    generate_impl_for_all_states, generate_instance_implementation, generate_meta_implementation,
+    generate_narrow_implementations, generate_serde_implementation,
+    generate_snapshot_implementations, generate_state_struct_declarations,
+    generate_stats_implementation, generate_trait_implementation,
+    generate_transaction_implementations, generate_unlocked_implementations,
+    generate_watch_implementation,
 };
 
-use quote::{format_ident, quote};
+use lockable::Lockable;
+use quote::{format_ident, quote, ToTokens};
 
 use state::State;
 use syn::Field;
@@ -12,6 +19,8 @@ use syn::Field;
 mod attribute;
 mod field;
 mod generators;
+mod hooks;
+mod lockable;
 mod path;
 mod state;
 
@@ -23,6 +32,31 @@ const RESULT: &str = "result";
 const LOCK_METHOD: &str = "lock_method";
 const INCLUDE: &str = "include";
 const EXCLUDE: &str = "exclude";
+const LOCKER: &str = "locker";
+const FLATTEN: &str = "flatten";
+const COLLECTION: &str = "collection";
+const KEYED: &str = "keyed";
+const ID: &str = "id";
+const STATS: &str = "stats";
+const TRY_LOCK_METHOD: &str = "try_lock_method";
+const WARN_HOLD: &str = "warn_hold";
+const OBSERVER: &str = "observer";
+const LEVEL: &str = "level";
+const WATCH: &str = "watch";
+const DEBUG: &str = "debug";
+const SERDE: &str = "serde";
+const SNAPSHOT: &str = "snapshot";
+const TRANSACTION: &str = "transaction";
+const MAX_LEVEL: u32 = 31;
+
+/// The options of `#[locker(...)]` attributes placed on the struct
+const STRUCT_OPTIONS: &[&str] = &[
+    STATS, WARN_HOLD, OBSERVER, LEVEL, DEBUG, SERDE, SNAPSHOT, TRANSACTION,
+];
+/// The options of `#[locker(...)]` attributes placed on a field
+const FIELD_OPTIONS: &[&str] = &[
+    FLATTEN, COLLECTION, KEYED, ID, TRY_LOCK_METHOD, WARN_HOLD, LEVEL, WATCH,
+];
 
 #[proc_macro_derive(
     Locker,
@@ -33,7 +67,8 @@ const EXCLUDE: &str = "exclude";
         lock_method,
         result,
         include,
-        exclude
+        exclude,
+        locker
     )
 )]
 pub fn locker_macro_derive(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
@@ -52,22 +87,28 @@ fn impl_locker_macro(ast: &syn::DeriveInput) -> proc_macro::TokenStream {
         panic!("Only applicable to structs")
     };
 
+    validate_options(&ast.attrs, fields);
+
     let all_ordered = if fields.iter().any(Field::is_included) {
         fields
             .iter()
-            .filter(|f| f.is_included())
-            .map(Field::to_owned)
-            .collect::<Vec<Field>>()
+            .filter(|f| f.is_included() && !f.is_id())
+            .flat_map(Lockable::from_field)
+            .collect::<Vec<Lockable>>()
     } else {
         fields
             .iter()
-            .filter(|f| !f.is_excluded())
-            .map(Field::to_owned)
-            .collect::<Vec<Field>>()
+            .filter(|f| !f.is_excluded() && !f.is_id())
+            .flat_map(Lockable::from_field)
+            .collect::<Vec<Lockable>>()
     };
 
+    validate_levels(&ast.attrs, &all_ordered);
+    validate_builder_methods(&ast.attrs, &all_ordered);
+
     let empty = State {
         struct_ident: ident.clone(),
+        struct_attrs: ast.attrs.clone(),
         active: Vec::new(),
         complements: all_ordered.clone(),
         all_ordered: all_ordered.clone(),
@@ -82,12 +123,123 @@ fn impl_locker_macro(ast: &syn::DeriveInput) -> proc_macro::TokenStream {
     let builder_struct = generate_builder_struct(&name, fields.iter());
     let impl_states = generate_impl_for_all_states(&name, &states, fields);
     let trait_implementation = generate_trait_implementation(ident, &name, &empty_name, fields);
+    let instance_implementation = generate_instance_implementation(ident, fields, &all_ordered);
+    let has_traits = generate_has_traits(ident, &all_ordered, &states);
+    let narrow_implementations = generate_narrow_implementations(&states);
+    let unlocked_implementations = generate_unlocked_implementations(&states);
+    let transaction_implementations =
+        generate_transaction_implementations(&name, &ast.attrs, &states);
+    let snapshot_implementations = generate_snapshot_implementations(&name, &ast.attrs, &states);
+    let meta_implementation = generate_meta_implementation(ident, &all_ordered);
+    let stats_implementation = generate_stats_implementation(ident, &ast.attrs, &all_ordered);
+    let watch_implementation = generate_watch_implementation(ident, &all_ordered);
+    let serde_implementation =
+        generate_serde_implementation(ident, &ast.attrs, fields, &all_ordered);
+    let debug_implementation =
+        generate_debug_implementation(ident, &ast.attrs, fields, &all_ordered);
 
     quote! {
         #state_struct_declarations
         #builder_struct
         #impl_states
         #trait_implementation
+        #instance_implementation
+        #has_traits
+        #narrow_implementations
+        #unlocked_implementations
+        #transaction_implementations
+        #snapshot_implementations
+        #meta_implementation
+        #stats_implementation
+        #watch_implementation
+        #serde_implementation
+        #debug_implementation
     }
     .into()
 }
+
+/// Ensures that either no field or every field has a lock level, and that levels do not decrease
+/// in lock order
+fn validate_levels(struct_attrs: &[syn::Attribute], all_ordered: &[Lockable]) {
+    let levels: Vec<Option<u32>> = all_ordered
+        .iter()
+        .map(|lockable| lockable.field.level(struct_attrs))
+        .collect();
+
+    if levels.iter().all(Option::is_none) {
+        return;
+    }
+    let levels: Vec<u32> = levels
+        .into_iter()
+        .map(|level| {
+            level.expect("Either all fields or none must have a level, consider setting a level on the struct")
+        })
+        .collect();
+
+    if let Some(level) = levels.iter().find(|level| **level > MAX_LEVEL) {
+        panic!("Level {} exceeds the maximum level of {}", level, MAX_LEVEL);
+    }
+    if levels.windows(2).any(|pair| pair[0] > pair[1]) {
+        panic!("Levels must not decrease in lock order");
+    }
+}
+
+/// Panics if a field is named like a method of the builders enabled through a struct attribute, as
+/// the builders already have a method named after each field
+fn validate_builder_methods(struct_attrs: &[syn::Attribute], all_ordered: &[Lockable]) {
+    let methods = [(TRANSACTION, "lock_transaction"), (SNAPSHOT, "snapshot")];
+
+    for (attribute, method) in methods {
+        if struct_attrs.locker_arg(attribute).is_none() {
+            continue;
+        }
+        if let Some(field) = all_ordered
+            .iter()
+            .find(|lockable| lockable.field.ident.as_ref().map_or(false, |ident| ident == method))
+        {
+            panic!(
+                "The field `{}` clashes with the `{}` method of the builders enabled through \
+                `#[locker({})]`, consider renaming the field",
+                field.field.ident.as_ref().expect("Fields must be named"),
+                method,
+                attribute
+            );
+        }
+    }
+}
+
+/// Panics if a `#[locker(...)]` option is unknown, or placed on a field while belonging on the
+/// struct or the other way around
+fn validate_options(struct_attrs: &[syn::Attribute], fields: &syn::Fields) {
+    check_options(struct_attrs, STRUCT_OPTIONS, "the struct", FIELD_OPTIONS, "a field");
+    for field in fields {
+        check_options(&field.attrs, FIELD_OPTIONS, "a field", STRUCT_OPTIONS, "the struct");
+    }
+}
+
+fn check_options(
+    attrs: &[syn::Attribute],
+    options: &[&str],
+    placement: &str,
+    other_options: &[&str],
+    other_placement: &str,
+) {
+    for arg in attrs.iter().flat_map(AttributeAugment::locker_args) {
+        let name = arg.path().to_token_stream().to_string().replace(' ', "");
+        if options.contains(&name.as_str()) {
+            continue;
+        }
+        if other_options.contains(&name.as_str()) {
+            panic!(
+                "The option `{}` must be placed on {} rather than on {}",
+                name, other_placement, placement
+            );
+        }
+        panic!(
+            "Unknown option `{}` on {}, expected one of {}",
+            name,
+            placement,
+            options.join(", ")
+        );
+    }
+}
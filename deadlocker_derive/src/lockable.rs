@@ -0,0 +1,68 @@
+use proc_macro2::Ident;
+use quote::format_ident;
+use syn::Field;
+
+use crate::field::FieldAugment;
+
+/// A single entry in the canonical lock order
+///
+/// Most fields map to exactly one [Lockable], while fields marked with `flatten` contribute one
+/// [Lockable] for each of the listed fields of the nested struct
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Lockable {
+    pub field: Field,
+    pub nested: Option<Ident>,
+}
+
+impl Lockable {
+    /// Expands a [syn::Field] into the [Lockable]s it contributes
+    pub fn from_field(field: &Field) -> Vec<Lockable> {
+        match field.flattened() {
+            Some(nested) => nested
+                .into_iter()
+                .map(|nested| Lockable {
+                    field: field.to_owned(),
+                    nested: Some(nested),
+                })
+                .collect(),
+            None => vec![Lockable {
+                field: field.to_owned(),
+                nested: None,
+            }],
+        }
+    }
+
+    /// Returns the identifier of the field this [Lockable] stems from
+    pub fn ident(&self) -> &Ident {
+        self.field.ident.as_ref().expect("Fields must be named")
+    }
+
+    /// Returns the identifier of the builder method selecting this [Lockable]
+    ///
+    /// Nested fields are namespaced by the flattened field, such as `session_user`
+    pub fn method_ident(&self) -> Ident {
+        match &self.nested {
+            Some(nested) => format_ident!("{}_{}", self.ident(), nested),
+            None => self.ident().to_owned(),
+        }
+    }
+
+    /// Returns whether locking this [Lockable] may fail
+    ///
+    /// Flattened fields are always fallible, as the nested struct decides whether its locks are,
+    /// and so are `collection` and `keyed` fields, as the selected elements may not exist
+    pub fn is_fallible(&self) -> bool {
+        self.nested.is_some() || self.field.selection_type().is_some() || self.field.is_result()
+    }
+}
+
+/// Returns the distinct fields contributing the [Lockable]s, in lock order
+pub fn ordered_fields(all_ordered: &[Lockable]) -> Vec<&Field> {
+    let mut fields: Vec<&Field> = Vec::new();
+    for lockable in all_ordered {
+        if !fields.contains(&&lockable.field) {
+            fields.push(&lockable.field);
+        }
+    }
+    fields
+}
@@ -5,69 +5,261 @@ use proc_macro2::{Ident, TokenStream};
 use quote::{format_ident, quote, ToTokens};
 use syn::Field;
 
-use crate::field::FieldAugment;
+use crate::{
+    field::FieldAugment,
+    hooks::{field_info, Hooks},
+    lockable::Lockable,
+};
 
 #[derive(PartialEq, Eq, Debug)]
 pub struct State {
     pub struct_ident: Ident,
-    pub active: Vec<Field>,
-    pub complements: Vec<Field>,
-    pub all_ordered: Vec<Field>,
+    pub struct_attrs: Vec<syn::Attribute>,
+    pub active: Vec<Lockable>,
+    pub complements: Vec<Lockable>,
+    pub all_ordered: Vec<Lockable>,
+}
+
+/// A run of consecutive [Lockable]s in a [State] stemming from the same [syn::Field]
+pub struct Group<'s> {
+    pub field: &'s Field,
+    pub nested: Vec<&'s Ident>,
 }
 
 impl State {
-    /// Computes the state resulting from adding a [syn::Field] to the existing state
-    pub fn add_state(&self, field: &Field) -> State {
+    /// Computes the state resulting from adding a [Lockable] to the existing state
+    pub fn add_state(&self, lockable: &Lockable) -> State {
         let reduced_complement = self
             .complements
             .iter()
-            .filter(|existing_field| *existing_field != field)
-            .map(Field::to_owned)
+            .filter(|existing| *existing != lockable)
+            .map(Lockable::to_owned)
             .collect();
 
         let mut expanded_active = self.active.clone();
-        expanded_active.push(field.to_owned());
+        expanded_active.push(lockable.to_owned());
 
         let active_ordered = self
             .all_ordered
             .iter()
             .filter(|possible| expanded_active.contains(possible))
-            .map(Field::to_owned)
+            .map(Lockable::to_owned)
             .collect();
 
         State {
             struct_ident: self.struct_ident.clone(),
+            struct_attrs: self.struct_attrs.clone(),
             active: active_ordered,
             complements: reduced_complement,
             all_ordered: self.all_ordered.clone(),
         }
     }
 
+    /// Groups the active [Lockable]s by the field they stem from, preserving the lock order
+    pub fn groups(&self) -> Vec<Group<'_>> {
+        let mut groups: Vec<Group<'_>> = Vec::new();
+        for lockable in &self.active {
+            match groups.last_mut() {
+                Some(group) if *group.field == lockable.field => {
+                    group.nested.extend(lockable.nested.as_ref())
+                }
+                _ => groups.push(Group {
+                    field: &lockable.field,
+                    nested: lockable.nested.iter().collect(),
+                }),
+            }
+        }
+        groups
+    }
+
     /// Returns a [proc_macro2::TokenStream] containing the assignments of the lock results for each field in the state
-    pub fn locked_fields(&self) -> Vec<TokenStream> {
-        self.active
+    ///
+    /// The locks are taken from the builder, binding the sources of each field as named by
+    /// [State::source_idents], or when relocking, from the already bound sources
+    pub fn locked_fields(&self, relock: bool) -> Vec<TokenStream> {
+        let hooks = Hooks::for_state(self);
+
+        self.groups()
             .iter()
-            .map(|f| {
+            .map(|group| {
+                let f = group.field;
                 let ident = &f.ident;
-                let res = if f.is_result() {
-                    quote! {?}
-                } else {
+                let source = source_ident(f);
+
+                if f.flattened().is_some() {
+                    let nested = &group.nested;
+                    let locked = match (relock, f.is_async()) {
+                        (false, true) => quote! {self.#ident.locker()#(.#nested())*.__lock_nested().await},
+                        (false, false) => quote! {self.#ident.locker()#(.#nested())*.__lock_nested_sync()},
+                        (true, is_async) => {
+                            let suffix = nested
+                                .iter()
+                                .map(|nested| pascal_case(&nested.to_string()))
+                                .collect::<String>();
+                            let nested_state = f.nested_path(&format!("Locker{}", suffix));
+                            if is_async {
+                                quote! {#nested_state::__relock_nested(#source, __location).await}
+                            } else {
+                                quote! {#nested_state::__relock_nested_sync(#source, __location)}
+                            }
+                        }
+                    };
+                    return quote! {
+                        let #ident = #locked?;
+                        let #source = #ident.__sources();
+                    };
+                }
+
+                let info = field_info(&self.struct_ident, &self.all_ordered, f);
+                let before = hooks.before(f, &info);
+                let acquire = hooks.acquire(f, &info);
+                let check = hooks.check(&info);
+                let after = hooks.after(f, &info);
+                let guard = hooks.wrap(f, quote! {guard});
+
+                if let Some(selection) = f.selection_ident() {
+                    let name = ident.as_ref().map(Ident::to_string);
+                    let return_type = f.return_type();
+                    let element = if f.is_keyed() {
+                        quote! {
+                            collection.get(&key).ok_or_else(|| ::deadlocker::LockError::MissingKey {
+                                field: #name,
+                                key: format!("{:?}", key),
+                            })#check
+                        }
+                    } else {
+                        quote! {
+                            collection.get(key).ok_or(::deadlocker::LockError::IndexOutOfBounds {
+                                field: #name,
+                                index: key,
+                                len: collection.len(),
+                            })#check
+                        }
+                    };
+                    let bind_source = if relock {
+                        quote! {}
+                    } else {
+                        quote! {
+                            let #source = {
+                                let mut selection = self.#selection;
+                                selection.sort();
+                                selection.dedup();
+                                (&*self.#ident, selection)
+                            };
+                        }
+                    };
+                    return quote! {
+                        #bind_source
+                        let #ident = {
+                            let collection: &'a _ = #source.0;
+                            let selection = #source.1.clone();
+
+                            let mut elements = Vec::with_capacity(selection.len());
+                            for key in selection {
+                                let element = #element;
+                                #before
+                                #acquire
+                                #after
+                                let guard: Box<::deadlocker::guard::Dirty<dyn std::ops::DerefMut<Target = #return_type> + 'a>> =
+                                    Box::new(#guard);
+                                elements.push((key, guard));
+                            }
+                            ::deadlocker::Elements::new(elements)
+                        };
+                    };
+                }
+
+                let bind_source = if relock {
                     quote! {}
+                } else {
+                    quote! {let #source = &*self.#ident;}
                 };
-                let lock_method = &f.lock_method();
                 quote! {
-                    let #ident = Box::new(self.#ident.#lock_method #res);
+                    #bind_source
+                    let #ident = {
+                        let element = #source;
+                        #before
+                        #acquire
+                        #after
+                        Box::new(#guard)
+                    };
                 }
                 .into_token_stream()
             })
             .collect()
     }
 
+    /// Returns the identifiers bound to the sources of each locked field by
+    /// [State::locked_fields], in lock order
+    pub fn source_idents(&self) -> Vec<Ident> {
+        self.groups()
+            .iter()
+            .map(|group| source_ident(group.field))
+            .collect()
+    }
+
+    /// Returns the type of the sources kept by the state to reacquire its locks
+    ///
+    /// These are references to the locks of single fields, references to the collections of
+    /// `collection` and `keyed` fields along with the selected keys, and the sources of the
+    /// nested state for flattened fields
+    pub fn sources_type(&self) -> TokenStream {
+        let types = self.groups().into_iter().map(|group| {
+            let ty = &group.field.ty;
+            if group.field.flattened().is_some() {
+                let nested_sources = nested_hidden_path(&group, sources_ident);
+                quote! {#nested_sources<'a>}
+            } else if let Some(selection_type) = group.field.selection_type() {
+                quote! {(&'a #ty, Vec<#selection_type>)}
+            } else {
+                quote! {&'a #ty}
+            }
+        });
+
+        quote! {(#(#types,)*)}
+    }
+
+    /// Returns the type of the dirty state kept while the locks of the state are released, so that
+    /// the reacquired guards count as mutably accessed if they were before
+    ///
+    /// These are whether single fields were dirty, the dirty keys of `collection` and `keyed`
+    /// fields, and the dirty state of the nested state for flattened fields
+    pub fn dirty_type(&self) -> TokenStream {
+        let types = self.groups().into_iter().map(|group| {
+            if group.field.flattened().is_some() {
+                let nested_dirty = nested_hidden_path(&group, dirty_ident);
+                quote! {#nested_dirty}
+            } else if let Some(selection_type) = group.field.selection_type() {
+                quote! {Vec<#selection_type>}
+            } else {
+                quote! {bool}
+            }
+        });
+
+        quote! {(#(#types,)*)}
+    }
+
     /// Returns whether any of the fields are asynchronous
     ///
-    /// See [FieldHelpers::is_async]
+    /// See [FieldAugment::is_async]
     pub fn is_async(&self) -> bool {
-        self.active.iter().any(Field::is_async)
+        self.active.iter().any(|l| l.field.is_async())
+    }
+
+    /// Returns the lowest and highest lock levels of the fields, if they have levels
+    ///
+    /// As levels do not decrease in lock order, these are the levels of the first and last field
+    pub fn levels(&self) -> Option<(u32, u32)> {
+        let first = self.active.first()?.field.level(&self.struct_attrs)?;
+        let last = self.active.last()?.field.level(&self.struct_attrs)?;
+        Some((first, last))
+    }
+
+    /// Returns whether locking any of the fields may fail
+    ///
+    /// See [Lockable::is_fallible]
+    pub fn is_fallible(&self) -> bool {
+        self.active.iter().any(Lockable::is_fallible)
     }
 
     /// Return a formatted identifier for the state
@@ -78,8 +270,8 @@ impl State {
     /// Returns a [Vec] of all possible sub-[State]s, including this [State]
     pub fn into_substates(self) -> Vec<State> {
         let mut explored = Vec::new();
-        for field in &self.complements {
-            let new_state = self.add_state(field);
+        for lockable in &self.complements {
+            let new_state = self.add_state(lockable);
 
             for possible_state in new_state.into_substates() {
                 if !explored.contains(&possible_state) {
@@ -107,32 +299,7 @@ impl Display for State {
         let name = self
             .active
             .iter()
-            .filter_map(|state| state.ident.as_ref().map(|i| i.to_string()))
-            .map(|state| {
-                let mut c = state.chars();
-                match c.next() {
-                    None => String::new(),
-                    Some(f) => f.to_uppercase().collect::<String>() + c.as_str(),
-                }
-            })
-            .map(|state| {
-                let mut modified = state;
-                while let Some(pos) = modified.find('_') {
-                    modified = modified
-                        .chars()
-                        .enumerate()
-                        .map(|(i, c)| {
-                            if i == pos + 1 {
-                                c.to_uppercase().collect()
-                            } else {
-                                c.to_string()
-                            }
-                        })
-                        .collect();
-                    modified = modified.replacen('_', "Underscore", 1);
-                }
-                modified
-            })
+            .map(|lockable| pascal_case(&lockable.method_ident().to_string()))
             .join("");
         if name.is_empty() {
             f.write_str("Empty")
@@ -141,3 +308,63 @@ impl Display for State {
         }
     }
 }
+
+/// Returns the identifier bound to the source of a field by [State::locked_fields]
+fn source_ident(field: &Field) -> Ident {
+    format_ident!(
+        "__source_{}",
+        field.ident.as_ref().expect("Fields must be named")
+    )
+}
+
+/// Returns the identifier of the hidden alias for the type of the sources of a state
+///
+/// Prefixed with underscores, as the name of a state followed by `Sources` may also be the name of
+/// another state, such as for a field named `sources`
+pub fn sources_ident(state_ident: &Ident) -> Ident {
+    format_ident!("__{}Sources", state_ident)
+}
+
+/// Returns the identifier of the hidden alias for the type of the dirty state of a state, see
+/// [State::dirty_type]
+pub fn dirty_ident(state_ident: &Ident) -> Ident {
+    format_ident!("__{}Dirty", state_ident)
+}
+
+/// Returns the path to a hidden type generated for the nested state of a flattened group
+fn nested_hidden_path(group: &Group<'_>, hidden_ident: fn(&Ident) -> Ident) -> syn::Path {
+    let suffix = group
+        .nested
+        .iter()
+        .map(|nested| pascal_case(&nested.to_string()))
+        .collect::<String>();
+    let mut path = group.field.nested_path(&format!("Locker{}", suffix));
+    let last = path.segments.last_mut().expect("Expected type");
+    last.ident = hidden_ident(&last.ident);
+    path
+}
+
+/// Converts a field name to the form used in generated identifiers, such as `foo_bar` to `FooUnderscoreBar`
+pub fn pascal_case(name: &str) -> String {
+    let mut c = name.chars();
+    let mut modified = match c.next() {
+        None => String::new(),
+        Some(f) => f.to_uppercase().collect::<String>() + c.as_str(),
+    };
+
+    while let Some(pos) = modified.find('_') {
+        modified = modified
+            .chars()
+            .enumerate()
+            .map(|(i, c)| {
+                if i == pos + 1 {
+                    c.to_uppercase().collect()
+                } else {
+                    c.to_string()
+                }
+            })
+            .collect();
+        modified = modified.replacen('_', "Underscore", 1);
+    }
+    modified
+}
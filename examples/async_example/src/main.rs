@@ -1,3 +1,5 @@
+#![allow(clippy::disallowed_names)]
+
 use deadlocker::Locker;
 use std::sync::Arc;
 use tokio::sync::Mutex;
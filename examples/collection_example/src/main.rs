@@ -0,0 +1,61 @@
+use deadlocker::Locker;
+use std::sync::{Arc, Mutex};
+
+type Shard = Vec<String>;
+type Index = usize;
+
+#[derive(Locker)]
+#[locker(stats)]
+pub struct Store {
+    #[result]
+    pub index: Arc<Mutex<Index>>,
+    #[result]
+    #[locker(collection)]
+    pub shards: Vec<Arc<Mutex<Shard>>>,
+}
+
+pub fn main() {
+    let mut store = Store {
+        index: Arc::new(Mutex::new(0)),
+        shards: (0..8).map(|_| Arc::new(Mutex::new(Vec::new()))).collect(),
+    };
+
+    {
+        let mut lock = store
+            .locker()
+            .shards([3, 1, 7])
+            .index()
+            .lock()
+            .expect("Mutex was poisoned");
+
+        for (index, shard) in lock.shards.iter_mut() {
+            shard.push(format!("entry in shard {}", index));
+        }
+        **lock.index += 3;
+    }
+
+    {
+        let lock = store
+            .locker()
+            .shards([7])
+            .lock()
+            .expect("Mutex was poisoned");
+
+        println!("Shard 7: {:?}", lock.shards[7]);
+    }
+
+    match store.locker().shards([8]).lock() {
+        Ok(_) => println!("Locked shard 8"),
+        Err(e) => println!("Could not lock shard 8: {}", e),
+    };
+
+    for stats in Store::lock_stats() {
+        println!(
+            "{}: {} acquisitions, {} contended, held for at most {:?}",
+            stats.field(),
+            stats.acquisitions(),
+            stats.contended(),
+            stats.max_hold()
+        );
+    }
+}
@@ -0,0 +1,85 @@
+use deadlocker::{Condvar, Locker};
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+};
+
+#[derive(Locker)]
+pub struct Queue {
+    #[result]
+    pub items: Arc<Mutex<Vec<u32>>>,
+    #[result]
+    pub closed: Arc<Mutex<bool>>,
+}
+
+impl Queue {
+    fn handle(&self) -> Queue {
+        Queue {
+            items: self.items.clone(),
+            closed: self.closed.clone(),
+        }
+    }
+}
+
+fn produce(mut queue: Queue, ready: &Condvar) {
+    for item in 0..10 {
+        let mut lock = queue
+            .locker()
+            .items()
+            .lock()
+            .expect("Mutex was poisoned");
+        lock.items.push(item);
+        drop(lock);
+        ready.notify_all();
+    }
+
+    let mut lock = queue
+        .locker()
+        .closed()
+        .lock()
+        .expect("Mutex was poisoned");
+    **lock.closed = true;
+    drop(lock);
+    ready.notify_all();
+}
+
+/// Takes items until the queue is closed and empty, returning their sum
+fn consume(mut queue: Queue, ready: &Condvar) -> u32 {
+    let mut sum = 0;
+
+    loop {
+        let lock = queue
+            .locker()
+            .items()
+            .closed()
+            .lock()
+            .expect("Mutex was poisoned");
+        let mut lock = lock
+            .wait_until(ready, |lock| !lock.items.is_empty() || **lock.closed)
+            .expect("Mutex was poisoned");
+
+        if lock.items.is_empty() {
+            return sum;
+        }
+        sum += lock.items.drain(..).sum::<u32>();
+    }
+}
+
+pub fn main() {
+    let queue = Queue {
+        items: Arc::new(Mutex::new(Vec::new())),
+        closed: Arc::new(Mutex::new(false)),
+    };
+    let ready = Arc::new(Condvar::new());
+
+    let consumer = {
+        let queue = queue.handle();
+        let ready = ready.clone();
+        thread::spawn(move || consume(queue, &ready))
+    };
+    produce(queue, &ready);
+
+    let sum = consumer.join().expect("Consumer panicked");
+    assert_eq!(sum, 45);
+    println!("Sum: {}", sum);
+}
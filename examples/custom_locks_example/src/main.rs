@@ -1,3 +1,5 @@
+#![allow(clippy::disallowed_names, clippy::arc_with_non_send_sync)]
+
 use deadlocker::Locker;
 use std::sync::Arc;
 
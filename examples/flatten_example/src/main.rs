@@ -0,0 +1,56 @@
+use deadlocker::Locker;
+use std::sync::{Arc, Mutex};
+
+type Config = Vec<String>;
+type User = String;
+type Token = u64;
+
+#[derive(Locker)]
+pub struct Session {
+    #[result]
+    pub user: Arc<Mutex<User>>,
+    #[result]
+    pub token: Arc<Mutex<Token>>,
+}
+
+#[derive(Locker)]
+pub struct AppState {
+    #[result]
+    pub config: Arc<Mutex<Config>>,
+    #[locker(flatten(user, token))]
+    pub session: Session,
+}
+
+pub fn main() {
+    let mut app_state = AppState {
+        config: Arc::new(Mutex::new(Vec::new())),
+        session: Session {
+            user: Arc::new(Mutex::new(String::new())),
+            token: Arc::new(Mutex::new(0)),
+        },
+    };
+
+    {
+        let mut lock = app_state
+            .locker()
+            .session_token()
+            .config()
+            .session_user()
+            .lock()
+            .expect("Mutex was poisoned");
+
+        lock.config.push(String::from("verbose"));
+        lock.session.user.push_str("admin");
+        **lock.session.token = 42;
+    }
+
+    {
+        let lock = app_state
+            .locker()
+            .session_user()
+            .lock()
+            .expect("Mutex was poisoned");
+
+        println!("User: {:?}", **lock.session.user);
+    }
+}
@@ -0,0 +1,58 @@
+use deadlocker::Locker;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+type Balance = i64;
+type History = Vec<Balance>;
+
+#[derive(Locker, Clone)]
+pub struct Account {
+    #[locker(id)]
+    pub number: u32,
+    #[result]
+    pub balance: Arc<Mutex<Balance>>,
+    #[result]
+    pub history: Arc<Mutex<History>>,
+}
+
+impl Account {
+    fn new(number: u32, balance: Balance) -> Self {
+        Account {
+            number,
+            balance: Arc::new(Mutex::new(balance)),
+            history: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+}
+
+fn transfer(from: &mut Account, to: &mut Account, amount: Balance) {
+    let (from, to) = deadlocker::lock_pair(from, to, |l| l.history().balance());
+    let mut from = from.expect("Mutex was poisoned");
+    let mut to = to.expect("Mutex was poisoned");
+
+    **from.balance -= amount;
+    from.history.push(-amount);
+    **to.balance += amount;
+    to.history.push(amount);
+}
+
+pub fn main() {
+    let a = Account::new(1, 100);
+    let b = Account::new(2, 100);
+
+    let handles = [(a.clone(), b.clone()), (b.clone(), a.clone())].map(|(mut from, mut to)| {
+        thread::spawn(move || {
+            for _ in 0..1000 {
+                transfer(&mut from, &mut to, 1);
+            }
+        })
+    });
+    for handle in handles {
+        handle.join().expect("Thread panicked");
+    }
+
+    let mut accounts = [a, b];
+    for lock in deadlocker::lock_all(accounts.iter_mut(), |l| l.balance()) {
+        println!("Balance: {}", **lock.expect("Mutex was poisoned").balance);
+    }
+}
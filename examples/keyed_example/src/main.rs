@@ -0,0 +1,57 @@
+use deadlocker::Locker;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+type AccountId = u32;
+type Balance = i64;
+
+#[derive(Locker, Clone)]
+pub struct Bank {
+    #[result]
+    #[locker(keyed)]
+    pub accounts: HashMap<AccountId, Arc<Mutex<Balance>>>,
+}
+
+fn transfer(bank: &mut Bank, from: AccountId, to: AccountId, amount: Balance) {
+    let mut lock = bank
+        .locker()
+        .accounts([from, to])
+        .lock()
+        .expect("Could not lock accounts");
+
+    lock.accounts[from] -= amount;
+    lock.accounts[to] += amount;
+}
+
+pub fn main() {
+    let bank = Bank {
+        accounts: (0..2).map(|id| (id, Arc::new(Mutex::new(100)))).collect(),
+    };
+
+    let handles = [(0, 1), (1, 0)].map(|(from, to)| {
+        let mut bank = bank.clone();
+        thread::spawn(move || {
+            for _ in 0..1000 {
+                transfer(&mut bank, from, to, 1);
+            }
+        })
+    });
+    for handle in handles {
+        handle.join().expect("Thread panicked");
+    }
+
+    let mut bank = bank;
+    let lock = bank
+        .locker()
+        .accounts([0, 1])
+        .lock()
+        .expect("Could not lock accounts");
+    println!("Balances: {:?}", lock.accounts.iter().collect::<Vec<_>>());
+    drop(lock);
+
+    match bank.locker().accounts([2]).lock() {
+        Ok(_) => println!("Locked account 2"),
+        Err(e) => println!("Could not lock account 2: {}", e),
+    };
+}
@@ -0,0 +1,71 @@
+use deadlocker::{
+    level::{Unlocked, L0},
+    LockToken, Locker,
+};
+use std::sync::{Arc, Mutex};
+
+#[derive(Locker)]
+#[locker(level = 0)]
+pub struct Config {
+    #[result]
+    pub max_users: Arc<Mutex<usize>>,
+}
+
+#[derive(Locker)]
+#[locker(level = 1)]
+pub struct Sessions {
+    #[result]
+    pub users: Arc<Mutex<Vec<String>>>,
+}
+
+/// May not be called while holding any locks, so it is free to lock both structs
+fn join(
+    config: &mut Config,
+    sessions: &mut Sessions,
+    token: &mut LockToken<Unlocked>,
+    user: &str,
+) -> bool {
+    let (config_lock, mut token) = config
+        .locker()
+        .max_users()
+        .lock(token)
+        .expect("Mutex was poisoned");
+    let (mut sessions_lock, _) = sessions
+        .locker()
+        .users()
+        .lock(&mut token)
+        .expect("Mutex was poisoned");
+
+    if sessions_lock.users.len() >= **config_lock.max_users {
+        return false;
+    }
+    sessions_lock.users.push(user.to_string());
+    true
+}
+
+/// May be called while holding locks of level 0, but not of level 1 or above
+fn count(sessions: &mut Sessions, token: &mut LockToken<L0>) -> usize {
+    let (lock, _) = sessions
+        .locker()
+        .users()
+        .lock(token)
+        .expect("Mutex was poisoned");
+
+    lock.users.len()
+}
+
+pub fn main() {
+    let mut config = Config {
+        max_users: Arc::new(Mutex::new(2)),
+    };
+    let mut sessions = Sessions {
+        users: Arc::new(Mutex::new(Vec::new())),
+    };
+    let mut token = LockToken::unlocked();
+
+    assert!(join(&mut config, &mut sessions, &mut token, "alice"));
+    assert!(join(&mut config, &mut sessions, &mut token, "bob"));
+    assert!(!join(&mut config, &mut sessions, &mut token, "carol"));
+
+    println!("Users: {}", count(&mut sessions, &mut token.raise::<L0>()));
+}
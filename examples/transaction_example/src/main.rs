@@ -0,0 +1,76 @@
+use deadlocker::Locker;
+use std::{
+    collections::HashMap,
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Mutex, PoisonError},
+};
+
+#[derive(Locker)]
+#[locker(transaction, snapshot)]
+pub struct Shop {
+    #[result]
+    pub inventory: Arc<Mutex<HashMap<String, u32>>>,
+    #[result]
+    pub orders: Arc<Mutex<Vec<String>>>,
+}
+
+/// Takes one of `item` from the inventory and records the order, changing nothing if it fails
+fn order(shop: &mut Shop, item: &str) -> Result<(), String> {
+    let transaction = shop
+        .locker()
+        .inventory()
+        .orders()
+        .lock_transaction()
+        .expect("Mutex was poisoned");
+
+    transaction.run(|lock| {
+        lock.orders.push(item.to_string());
+        let stock = lock
+            .inventory
+            .get_mut(item)
+            .ok_or_else(|| format!("Unknown item {}", item))?;
+        *stock = stock
+            .checked_sub(1)
+            .ok_or_else(|| format!("Out of {}", item))?;
+        Ok(())
+    })
+}
+
+pub fn main() {
+    let mut shop = Shop {
+        inventory: Arc::new(Mutex::new(HashMap::from([("apple".to_string(), 1)]))),
+        orders: Arc::new(Mutex::new(Vec::new())),
+    };
+
+    assert_eq!(order(&mut shop, "apple"), Ok(()));
+    assert_eq!(order(&mut shop, "apple"), Err("Out of apple".to_string()));
+    assert_eq!(order(&mut shop, "pear"), Err("Unknown item pear".to_string()));
+
+    let snapshot = shop
+        .locker()
+        .inventory()
+        .orders()
+        .snapshot()
+        .expect("Mutex was poisoned");
+    assert_eq!(snapshot.inventory["apple"], 0);
+    assert_eq!(snapshot.orders, ["apple"]);
+
+    // Panicking while the transaction is in progress rolls back as well, though the mutex ends up
+    // poisoned
+    panic::set_hook(Box::new(|_| {}));
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        let mut transaction = shop
+            .locker()
+            .orders()
+            .lock_transaction()
+            .expect("Mutex was poisoned");
+        transaction.orders.clear();
+        panic!("Interrupted");
+    }));
+    let _ = panic::take_hook();
+    assert!(result.is_err());
+
+    let orders = shop.orders.lock().unwrap_or_else(PoisonError::into_inner);
+    assert_eq!(*orders, ["apple"]);
+    println!("Orders: {:?}", *orders);
+}
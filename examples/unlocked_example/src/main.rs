@@ -0,0 +1,51 @@
+use deadlocker::Locker;
+use std::sync::{Arc, Mutex};
+
+/// The field named `sources` must not collide with the types generated for the other states
+#[derive(Locker)]
+pub struct Pipeline {
+    #[result]
+    pub input: Arc<Mutex<Vec<String>>>,
+    #[result]
+    pub sources: Arc<Mutex<Vec<String>>>,
+}
+
+/// Stands in for user code which needs the same locks as the caller
+fn callback(pipeline: &mut Pipeline) -> usize {
+    let lock = pipeline
+        .locker()
+        .sources()
+        .lock()
+        .expect("Mutex was poisoned");
+
+    lock.sources.len()
+}
+
+pub fn main() {
+    let mut pipeline = Pipeline {
+        input: Arc::new(Mutex::new(Vec::new())),
+        sources: Arc::new(Mutex::new(Vec::new())),
+    };
+    let mut handle = Pipeline {
+        input: pipeline.input.clone(),
+        sources: pipeline.sources.clone(),
+    };
+
+    let mut lock = pipeline
+        .locker()
+        .input()
+        .sources()
+        .lock()
+        .expect("Mutex was poisoned");
+    lock.input.push("line".to_string());
+    lock.sources.push("stdin".to_string());
+
+    let (lock, count) = lock
+        .unlocked(|| callback(&mut handle))
+        .expect("Mutex was poisoned");
+
+    assert_eq!(count, 1);
+    assert_eq!(**lock.input, vec!["line".to_string()]);
+    assert_eq!(lock.dirty_fields(), vec!["input", "sources"]);
+    println!("Sources: {:?}", **lock.sources);
+}